@@ -0,0 +1,170 @@
+//! Throughput on mobydick.txt, run with `cargo bench`.  Reassembly is timed against the per-byte
+//! window it replaced, and a whole transfer between two TCBs over loopback end to end.
+
+extern crate ece358;
+
+use ece358::reassembly::Reassembly;
+use ece358::segment::Segment;
+use ece358::tcp::{TCB, TCBInput, TCPTuple};
+use std::collections::VecDeque;
+use std::fs;
+use std::net::UdpSocket;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+const MSS: usize = 1500;
+const WINDOW: usize = 65000;
+// Segments arrive in reversed groups of this many, so most of them are out of order
+const REORDER: usize = 8;
+
+/// The receive window as it used to be, one `Option<u8>` per byte
+struct ByteWindow {
+    base: u32,
+    window: VecDeque<Option<u8>>,
+}
+
+impl ByteWindow {
+    fn insert(&mut self, seq: u32, data: &[u8], out: &mut Vec<u8>) {
+        let offset = seq.wrapping_sub(self.base) as usize;
+        for (i, &byte) in data.iter().enumerate() {
+            self.window[offset + i] = Some(byte);
+        }
+        while let Some(&Some(byte)) = self.window.front() {
+            out.push(byte);
+            self.base = self.base.wrapping_add(1);
+            self.window.pop_front();
+            self.window.push_back(None);
+        }
+    }
+}
+
+fn arrival_order(len: usize) -> Vec<(u32, usize, usize)> {
+    let segs: Vec<usize> = (0..len).step_by(MSS).collect();
+    let mut order = vec![];
+    for group in segs.chunks(REORDER) {
+        for &start in group.iter().rev() {
+            // Start the sequence space just short of wrapping
+            let seq = (u32::MAX - 1000).wrapping_add(start as u32);
+            order.push((seq, start, std::cmp::min(start + MSS, len)));
+        }
+    }
+    order
+}
+
+fn best_of<F: FnMut() -> Vec<u8>>(data: &[u8], mut run: F) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let out = run();
+            let elapsed = start.elapsed();
+            assert!(out == data);
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn mb_per_sec(len: usize, elapsed: Duration) -> f64 {
+    len as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+}
+
+fn bench_reassembly(data: &[u8]) {
+    let order = arrival_order(data.len());
+    let base = u32::MAX - 1000;
+
+    let per_byte = best_of(data, || {
+        let mut window = ByteWindow {
+            base,
+            window: VecDeque::from(vec![None; WINDOW]),
+        };
+        let mut out = Vec::with_capacity(data.len());
+        for &(seq, start, end) in &order {
+            window.insert(seq, &data[start..end], &mut out);
+        }
+        out
+    });
+
+    let ranges = best_of(data, || {
+        let mut buf = Reassembly::new();
+        let mut next = base;
+        let mut out = Vec::with_capacity(data.len());
+        for &(seq, start, end) in &order {
+            buf.insert(next, WINDOW, seq, &data[start..end]);
+            if let Some(run) = buf.pop(next) {
+                next = next.wrapping_add(run.len() as u32);
+                out.extend(run);
+            }
+        }
+        out
+    });
+
+    println!(
+        "reassembly, per byte: {:8.1} MB/s",
+        mb_per_sec(data.len(), per_byte)
+    );
+    println!(
+        "reassembly, ranges:   {:8.1} MB/s",
+        mb_per_sec(data.len(), ranges)
+    );
+}
+
+/// Feeds segments arriving on `sock` to a TCB
+fn pass_segments(sock: UdpSocket, input: Sender<TCBInput>) {
+    thread::spawn(move || {
+        let mut buf = vec![0; 1 << 16];
+        while let Ok((amt, _)) = sock.recv_from(&mut buf) {
+            let seg = match Segment::parse(&buf[..amt]) {
+                Ok(seg) => seg,
+                Err(_) => continue,
+            };
+            if input.send(TCBInput::Receive(seg)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn transfer(data: &[u8]) -> Vec<u8> {
+    let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_tuple = TCPTuple {
+        src: server_sock.local_addr().unwrap(),
+        dst: client_sock.local_addr().unwrap(),
+    };
+    let client_tuple = TCPTuple {
+        src: client_sock.local_addr().unwrap(),
+        dst: server_sock.local_addr().unwrap(),
+    };
+    let (mut server, server_input, _server_output) =
+        TCB::new(server_tuple, server_sock.try_clone().unwrap());
+    let (mut client, client_input, client_output) =
+        TCB::new(client_tuple, client_sock.try_clone().unwrap());
+    pass_segments(server_sock, server_input.clone());
+    pass_segments(client_sock, client_input.clone());
+    thread::spawn(move || server.run_tcp());
+    thread::spawn(move || client.run_tcp());
+
+    client_input.send(TCBInput::SendSyn).unwrap();
+    server_input.send(TCBInput::Send(data.to_vec())).unwrap();
+    let out = TCB::recv(&client_output, data.len() as u32).unwrap();
+    // Either side may already be gone, reset by the other's abort
+    let _ = server_input.send(TCBInput::Abort);
+    let _ = client_input.send(TCBInput::Abort);
+    out
+}
+
+fn bench_transfer(data: &[u8]) {
+    let elapsed = best_of(data, || transfer(data));
+    println!(
+        "loopback transfer:    {:8.1} MB/s",
+        mb_per_sec(data.len(), elapsed)
+    );
+}
+
+fn main() {
+    let data = fs::read("mobydick.txt").unwrap();
+    bench_reassembly(&data);
+    bench_transfer(&data);
+}
@@ -21,7 +21,7 @@ impl Config {
         };
 
         Ok(Config {
-            port: port,
+            port,
             filepath: PathBuf::from(filepath),
         })
     }
@@ -45,8 +45,8 @@ impl ClientConfig {
             None => return Err("Didn't get port"),
         };
         Ok(ClientConfig {
-            src_port: src_port,
-            dst_port: dst_port,
+            src_port,
+            dst_port,
         })
     }
 }
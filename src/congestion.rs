@@ -0,0 +1,449 @@
+use std::cmp::{min, max};
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// Duplicate ACKs that signal a lost segment, per RFC 5681
+pub const DUPE_ACK_THRESHOLD: u32 = 3;
+
+/// Decides how many bytes a TCB may have in flight.  The TCB reports every ACK, duplicate ACK,
+/// retransmission timeout and send, and when a hook returns true it retransmits its oldest
+/// unacknowledged segment.
+pub trait CongestionControl: Debug + Send {
+    /// The congestion window in bytes
+    fn cwnd(&self) -> usize;
+
+    /// Called once the handshake settles on the largest segment the peer accepts
+    fn set_mss(&mut self, mss: usize);
+
+    fn on_send(&mut self, _bytes: usize, _now: Instant) {}
+
+    /// `acked` bytes of new data were acknowledged up to `ack`, leaving `flight` outstanding
+    fn on_ack(&mut self, ack: u32, acked: usize, flight: usize, now: Instant) -> bool;
+
+    /// The `count`th ACK in a row that acknowledged nothing new, `snd_nxt` being the next
+    /// sequence number we'd send
+    fn on_dup_ack(&mut self, count: u32, snd_nxt: u32, flight: usize, now: Instant) -> bool;
+
+    fn on_timeout(&mut self, flight: usize, now: Instant);
+}
+
+/// Initial window from RFC 3390
+pub fn initial_window(mss: usize) -> usize {
+    min(4 * mss, max(2 * mss, 4380))
+}
+
+/// Whether `a` comes at or after `b` in sequence space
+pub fn seq_geq(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) < (1 << 31)
+}
+
+/// Window after a partial ACK during fast recovery (RFC 6582): deflate by the newly acknowledged
+/// data, then add back one segment if a full one left the network
+pub fn deflate(cwnd: usize, acked: usize, mss: usize) -> usize {
+    let cwnd = cwnd.saturating_sub(acked);
+    if acked >= mss {
+        cwnd + mss
+    } else {
+        cwnd
+    }
+}
+
+/// NewReno fast recovery state, shared by the algorithms that only differ in how they grow and
+/// shrink the window
+#[derive(Debug, Clone, Default)]
+pub struct Recovery {
+    // Highest sequence number sent when the loss was detected, recovery ends once it's ACKed
+    recover: Option<u32>,
+}
+
+impl Recovery {
+    pub fn in_recovery(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    pub fn enter(&mut self, snd_nxt: u32) {
+        self.recover = Some(snd_nxt);
+    }
+
+    pub fn exit(&mut self) {
+        self.recover = None;
+    }
+
+    /// Whether `ack` covers everything outstanding when recovery began
+    pub fn is_full_ack(&self, ack: u32) -> bool {
+        self.recover.is_none_or(|recover| seq_geq(ack, recover))
+    }
+}
+
+/// Reno with the NewReno modification to fast recovery (RFC 5681 and RFC 6582)
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // Bytes acknowledged towards the next increase during congestion avoidance
+    acked_bytes: usize,
+    recovery: Recovery,
+}
+
+impl NewReno {
+    pub fn new(mss: usize) -> NewReno {
+        NewReno {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            acked_bytes: 0,
+            recovery: Recovery::default(),
+        }
+    }
+
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn reduced_ssthresh(&self, flight: usize) -> usize {
+        max(flight / 2, 2 * self.mss)
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        self.cwnd = initial_window(mss);
+    }
+
+    fn on_ack(&mut self, ack: u32, acked: usize, flight: usize, _now: Instant) -> bool {
+        if self.recovery.in_recovery() {
+            if self.recovery.is_full_ack(ack) {
+                self.cwnd = min(self.ssthresh, max(flight, self.mss) + self.mss);
+                self.recovery.exit();
+                return false;
+            }
+            // A partial ACK means the segment after it was lost too, resend it without waiting
+            // for more duplicates
+            self.cwnd = deflate(self.cwnd, acked, self.mss);
+            return true;
+        }
+
+        if self.cwnd < self.ssthresh {
+            self.cwnd += min(acked, self.mss);
+        } else {
+            self.acked_bytes += acked;
+            if self.acked_bytes >= self.cwnd {
+                self.acked_bytes -= self.cwnd;
+                self.cwnd += self.mss;
+            }
+        }
+        false
+    }
+
+    fn on_dup_ack(&mut self, count: u32, snd_nxt: u32, flight: usize, _now: Instant) -> bool {
+        if self.recovery.in_recovery() {
+            // Each duplicate means a segment left the network
+            self.cwnd += self.mss;
+            return false;
+        }
+        if count != DUPE_ACK_THRESHOLD {
+            return false;
+        }
+        self.ssthresh = self.reduced_ssthresh(flight);
+        self.cwnd = self.ssthresh + 3 * self.mss;
+        self.acked_bytes = 0;
+        self.recovery.enter(snd_nxt);
+        true
+    }
+
+    fn on_timeout(&mut self, flight: usize, _now: Instant) {
+        self.ssthresh = self.reduced_ssthresh(flight);
+        self.cwnd = self.mss;
+        self.acked_bytes = 0;
+        self.recovery.exit();
+    }
+}
+
+/// Scales how fast CUBIC grows, in segments per second cubed
+const CUBIC_C: f64 = 0.4;
+/// Fraction of the window CUBIC keeps after a loss
+const CUBIC_BETA: f64 = 0.7;
+/// Additive increase per RTT that makes the Reno estimate match standard TCP's average window
+const CUBIC_ALPHA: f64 = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+
+/// Growth state since the last congestion event, windows are in segments
+#[derive(Debug, Clone)]
+struct CubicEpoch {
+    start: Instant,
+    // Time for the cubic function to climb back to `origin`
+    k: f64,
+    origin: f64,
+    // What Reno would have grown to by now, for the TCP-friendly region
+    w_est: f64,
+}
+
+/// CUBIC (RFC 9438).  The window grows as a cubic function of the time since the last loss, so
+/// it recovers quickly on long fat paths no matter the RTT, while never growing slower than Reno.
+/// Loss recovery itself follows NewReno.
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // Window in segments just before the last reduction
+    w_max: f64,
+    epoch: Option<CubicEpoch>,
+    recovery: Recovery,
+}
+
+impl Cubic {
+    pub fn new(mss: usize) -> Cubic {
+        Cubic {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            epoch: None,
+            recovery: Recovery::default(),
+        }
+    }
+
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn segments(&self, bytes: usize) -> f64 {
+        bytes as f64 / self.mss as f64
+    }
+
+    fn reduce(&mut self) {
+        let cwnd = self.segments(self.cwnd);
+        // Fast convergence, release bandwidth sooner when a new flow is pushing us down
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.ssthresh = max((self.cwnd as f64 * CUBIC_BETA) as usize, 2 * self.mss);
+        self.epoch = None;
+    }
+
+    fn congestion_avoidance(&mut self, acked: usize, now: Instant) {
+        let cwnd = self.segments(self.cwnd);
+        let w_max = self.w_max;
+        let epoch = self.epoch.get_or_insert_with(|| {
+            let (k, origin) = if cwnd < w_max {
+                (((w_max - cwnd) / CUBIC_C).cbrt(), w_max)
+            } else {
+                (0.0, cwnd)
+            };
+            CubicEpoch { start: now, k, origin, w_est: cwnd }
+        });
+
+        let t = duration_secs(now.saturating_duration_since(epoch.start));
+        let w_cubic = CUBIC_C * (t - epoch.k).powi(3) + epoch.origin;
+        epoch.w_est += CUBIC_ALPHA * (acked as f64 / self.mss as f64) / cwnd;
+
+        let next = if w_cubic < epoch.w_est {
+            // TCP-friendly region, Reno would do better so behave like it
+            epoch.w_est
+        } else {
+            let target = w_cubic.min(1.5 * cwnd);
+            cwnd + (target - cwnd).max(0.0) / cwnd * (acked as f64 / self.mss as f64)
+        };
+        self.cwnd = max(self.cwnd, (next * self.mss as f64) as usize);
+    }
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+impl CongestionControl for Cubic {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        self.cwnd = initial_window(mss);
+    }
+
+    fn on_ack(&mut self, ack: u32, acked: usize, _flight: usize, now: Instant) -> bool {
+        if self.recovery.in_recovery() {
+            if self.recovery.is_full_ack(ack) {
+                self.cwnd = self.ssthresh;
+                self.recovery.exit();
+                return false;
+            }
+            self.cwnd = deflate(self.cwnd, acked, self.mss);
+            return true;
+        }
+
+        if self.cwnd < self.ssthresh {
+            self.cwnd += min(acked, self.mss);
+        } else {
+            self.congestion_avoidance(acked, now);
+        }
+        false
+    }
+
+    fn on_dup_ack(&mut self, count: u32, snd_nxt: u32, _flight: usize, _now: Instant) -> bool {
+        if self.recovery.in_recovery() {
+            self.cwnd += self.mss;
+            return false;
+        }
+        if count != DUPE_ACK_THRESHOLD {
+            return false;
+        }
+        self.reduce();
+        self.cwnd = self.ssthresh + 3 * self.mss;
+        self.recovery.enter(snd_nxt);
+        true
+    }
+
+    fn on_timeout(&mut self, _flight: usize, _now: Instant) {
+        self.reduce();
+        self.cwnd = self.mss;
+        self.recovery.exit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    #[test]
+    fn slow_start() {
+        let now = Instant::now();
+        let mut reno = NewReno::new(MSS);
+        assert_eq!(reno.cwnd(), 4000);
+        for i in 0..4 {
+            assert!(!reno.on_ack(i * 1000, MSS, 3000, now));
+        }
+        assert_eq!(reno.cwnd(), 8000);
+    }
+
+    #[test]
+    fn congestion_avoidance() {
+        let now = Instant::now();
+        let mut reno = NewReno::new(MSS);
+        reno.on_timeout(20_000, now);
+        assert_eq!(reno.cwnd(), MSS);
+        assert_eq!(reno.ssthresh(), 10_000);
+
+        while reno.cwnd() < reno.ssthresh() {
+            reno.on_ack(0, MSS, 0, now);
+        }
+        assert_eq!(reno.cwnd(), 10_000);
+
+        // One MSS per window's worth of ACKs
+        for _ in 0..10 {
+            reno.on_ack(0, MSS, 0, now);
+        }
+        assert_eq!(reno.cwnd(), 11_000);
+    }
+
+    #[test]
+    fn fast_recovery() {
+        let now = Instant::now();
+        let mut reno = NewReno::new(MSS);
+        assert!(!reno.on_dup_ack(1, 10_000, 8000, now));
+        assert!(!reno.on_dup_ack(2, 10_000, 8000, now));
+        assert!(reno.on_dup_ack(3, 10_000, 8000, now));
+        assert_eq!(reno.ssthresh(), 4000);
+        assert_eq!(reno.cwnd(), 7000);
+
+        assert!(!reno.on_dup_ack(4, 10_000, 8000, now));
+        assert_eq!(reno.cwnd(), 8000);
+
+        // Partial ACK, the next hole gets retransmitted and the window deflates
+        assert!(reno.on_ack(4000, 2000, 6000, now));
+        assert_eq!(reno.cwnd(), 7000);
+
+        // Full ACK ends recovery
+        assert!(!reno.on_ack(10_000, 6000, 0, now));
+        assert_eq!(reno.cwnd(), 2000);
+        assert!(!reno.recovery.in_recovery());
+    }
+
+    /// Cubic that just lost a packet at a 100 segment window and has finished recovering
+    fn cubic_after_loss() -> Cubic {
+        let mut cubic = Cubic::new(MSS);
+        cubic.cwnd = 100 * MSS;
+        cubic.ssthresh = 50 * MSS;
+        assert!(cubic.on_dup_ack(3, 1, 0, Instant::now()));
+        assert!(!cubic.on_ack(1, MSS, 0, Instant::now()));
+        cubic
+    }
+
+    /// ACKs a full window every 100ms of virtual time, between `from` and `until` ms past `start`
+    fn ack_rounds(cubic: &mut Cubic, start: Instant, from: u64, until: u64) {
+        for elapsed in (from..until).step_by(100) {
+            for _ in 0..cubic.cwnd() / MSS {
+                cubic.on_ack(0, MSS, 0, start + Duration::from_millis(elapsed));
+            }
+        }
+    }
+
+    #[test]
+    fn cubic_reduction() {
+        let mut cubic = cubic_after_loss();
+        assert_eq!(cubic.ssthresh(), 70 * MSS);
+        assert_eq!(cubic.cwnd(), 70 * MSS);
+
+        // Losing again below the old maximum gives up some of it
+        cubic.on_timeout(0, Instant::now());
+        assert_eq!(cubic.cwnd(), MSS);
+        assert_eq!(cubic.ssthresh(), 49 * MSS);
+        assert!((cubic.w_max - 59.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cubic_growth() {
+        let mut cubic = cubic_after_loss();
+        let start = Instant::now();
+        // K = cbrt(100 * 0.3 / 0.4), about 4.2s to get back to the old maximum
+        ack_rounds(&mut cubic, start, 0, 2000);
+        let concave = cubic.cwnd();
+        assert!(concave > 80 * MSS && concave < 100 * MSS);
+
+        // Plateaus around the old maximum
+        ack_rounds(&mut cubic, start, 2000, 4200);
+        let plateau = cubic.cwnd();
+        assert!(plateau > 97 * MSS && plateau <= 101 * MSS);
+        ack_rounds(&mut cubic, start, 4200, 5000);
+        assert!(cubic.cwnd() - plateau < 2 * MSS);
+
+        // Then probes well past it
+        ack_rounds(&mut cubic, start, 5000, 8000);
+        assert!(cubic.cwnd() > 115 * MSS);
+    }
+
+    #[test]
+    fn cubic_tcp_friendly() {
+        // With the clock standing still the cubic function doesn't grow at all, so any growth
+        // comes from the Reno estimate
+        let mut cubic = cubic_after_loss();
+        let now = Instant::now();
+        for _ in 0..10 {
+            for _ in 0..cubic.cwnd() / MSS {
+                cubic.on_ack(0, MSS, 0, now);
+            }
+        }
+        let grown = cubic.segments(cubic.cwnd() - 70 * MSS);
+        assert!((grown - 10.0 * CUBIC_ALPHA).abs() < 0.5);
+    }
+
+    #[test]
+    fn recovery_across_wraparound() {
+        let mut recovery = Recovery::default();
+        recovery.enter(5);
+        assert!(!recovery.is_full_ack(u32::MAX - 10));
+        assert!(recovery.is_full_ack(5));
+        assert!(recovery.is_full_ack(100));
+    }
+}
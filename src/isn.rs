@@ -0,0 +1,62 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tcp::TCPTuple;
+
+/// Microseconds per tick of the ISN clock, from RFC 6528
+const CLOCK_TICK_US: u128 = 4;
+
+/// Key for the hash, picked at random once per process
+fn secret() -> &'static RandomState {
+    static SECRET: OnceLock<RandomState> = OnceLock::new();
+    SECRET.get_or_init(RandomState::new)
+}
+
+/// Hash keyed with this process's secret, nobody without the key can predict it
+pub fn keyed_hash<T: Hash>(value: T) -> u64 {
+    secret().hash_one(value)
+}
+
+/// Initial sequence number for a new connection on `tuple` per RFC 6528.  The clock keeps a new
+/// incarnation of a tuple ahead of the old one's sequence numbers, while the hash keeps them
+/// unguessable from outside.
+pub fn generate(tuple: &TCPTuple) -> u32 {
+    generate_at(tuple, SystemTime::now())
+}
+
+fn generate_at(tuple: &TCPTuple, now: SystemTime) -> u32 {
+    let ticks = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() / CLOCK_TICK_US;
+    (keyed_hash(tuple) as u32).wrapping_add(ticks as u32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn tuple(src_port: u16) -> TCPTuple {
+        TCPTuple {
+            src: format!("127.0.0.1:{}", src_port).parse().unwrap(),
+            dst: "127.0.0.1:2000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn advances_with_clock() {
+        let now = SystemTime::now();
+        let isn = generate_at(&tuple(1000), now);
+        let later = generate_at(&tuple(1000), now + Duration::from_micros(400));
+        assert_eq!(later.wrapping_sub(isn), 100);
+    }
+
+    #[test]
+    fn differs_per_tuple() {
+        let now = SystemTime::now();
+        assert_ne!(generate_at(&tuple(1000), now), generate_at(&tuple(1001), now));
+    }
+}
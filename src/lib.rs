@@ -2,8 +2,15 @@ pub mod utils;
 pub mod tcp;
 pub mod segment;
 pub mod config;
+pub mod rto;
+pub mod congestion;
+pub mod isn;
+pub mod syncookie;
+pub mod reassembly;
+pub mod sendbuf;
+pub mod sim;
 use tcp::*;
-use std::str;
+use std::io;
 use std::net::*;
 use config::*;
 use segment::*;
@@ -11,9 +18,18 @@ use utils::*;
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::collections::hash_map::Entry;
-use std::sync::mpsc::{Sender, Receiver, RecvError, SendError};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, SendError};
 use std::fs::{File, OpenOptions};
 use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Half-open connections the server holds state for before it answers SYNs with cookies
+pub const SYN_COOKIE_THRESHOLD: usize = 64;
+/// How long a handshake may take before it no longer counts as pending
+const HANDSHAKE_TIMEOUT: u64 = 75; // In seconds
+/// Longest the server waits for a datagram before checking for closed connections
+const REAP_INTERVAL: u64 = 1; // In seconds
 
 
 fn tuple_to_filename(tuple: &TCPTuple) -> String {
@@ -27,10 +43,9 @@ fn tuple_to_filename(tuple: &TCPTuple) -> String {
 }
 
 fn get_file(tuple: &TCPTuple, folder: &Path) -> Result<File, std::io::Error> {
-    let filepath = folder.join(tuple_to_filename(&tuple));
+    let filepath = folder.join(tuple_to_filename(tuple));
     let file = OpenOptions::new()
         .read(true)
-        .write(true)
         .append(true)
         .create(true)
         .open(filepath)?;
@@ -46,12 +61,12 @@ fn send_str(tcb_input: &Sender<TCBInput>, s: String) -> Result<(), SendError<TCB
     Ok(())
 }
 
-fn recv_str(tcb_output: &Receiver<u8>) -> Result<String, RecvError> {
-    let size = buf_to_u32(&TCB::recv(&tcb_output, 4)?[..]);
-    Ok(String::from_utf8(TCB::recv(&tcb_output, size)?).unwrap())
+fn recv_str(tcb_output: &TCBOutput) -> Result<String, ConnectionError> {
+    let size = buf_to_u32(&TCB::recv(tcb_output, 4)?[..]);
+    Ok(String::from_utf8(TCB::recv(tcb_output, size)?).unwrap())
 }
 
-fn run_server_tcb(config: Config, tuple: TCPTuple, input: Sender<TCBInput>, output: Receiver<u8>) {
+fn run_server_tcb(config: Config, tuple: TCPTuple, input: Sender<TCBInput>, output: TCBOutput) {
     let mut file = if let Ok(file) = get_file(&tuple, config.filepath.as_path()) {
         file
     } else {
@@ -61,13 +76,13 @@ fn run_server_tcb(config: Config, tuple: TCPTuple, input: Sender<TCBInput>, outp
 
     let mut s = String::new();
     file.read_to_string(&mut s).unwrap();
-    send_str(&input, s).unwrap_or_else(|_| return);
+    let _ = send_str(&input, s);
 
     'main_application_loop: loop {
         match recv_str(&output) {
             Ok(data) => {
                 // println!("Got string: {:?}", data);
-                file.write_all(&data.as_bytes()).unwrap();
+                file.write_all(data.as_bytes()).unwrap();
                 // Errors when Closed
                 if send_str(&input, data).is_err() {
                     break 'main_application_loop;
@@ -77,110 +92,255 @@ fn run_server_tcb(config: Config, tuple: TCPTuple, input: Sender<TCBInput>, outp
         }
     }
     file.sync_all().unwrap();
+    // The client has closed its half, close ours so the connection can finish
+    let _ = input.send(TCBInput::Close);
     println!("Server TCB Ending");
 }
 
-fn multiplexed_receive(
-    config: &Config,
-    channels: &mut HashMap<TCPTuple, Sender<TCBInput>>,
-    socket: &UdpSocket,
-) -> Result<(), ()> {
-    let mut buf = vec![0; (1 << 16) - 1];
-    match socket.recv_from(&mut buf) {
-        Ok((amt, src)) => {
-            buf.truncate(amt);
-            let seg = Segment::from_buf(buf.clone());
-            if seg.validate() {
-                let tuple = TCPTuple {
-                    src: socket.local_addr().unwrap(),
-                    dst: src, // Send replies to the sender
-                };
-                let mut valid_channel_found = false;
-                match channels.entry(tuple) {
-                    Entry::Occupied(entry) => {
-                        let seg_copy = seg.clone();
-                        match entry.into_mut().send(TCBInput::Receive(seg_copy)) {
-                            Ok(_) => {
-                                valid_channel_found = true;
-                            }
-                            Err(_) => {}
-                        }
-                    }
-                    _ => {}
-                }
-                if !valid_channel_found {
-                    channels.remove(&tuple);
-                }
-                match channels.entry(tuple) {
-                    Entry::Vacant(v) => {
-                        println!("New connection! {:?}", tuple);
-                        let (mut tcb, input, output) = TCB::new(tuple, socket.try_clone().unwrap());
-                        let udp_sender = input.clone();
-                        udp_sender.send(TCBInput::Receive(seg)).unwrap();
-                        v.insert(udp_sender);
-                        let config = config.clone();
-                        std::thread::spawn(move || tcb.run_tcp());
-                        std::thread::spawn(
-                            move || { run_server_tcb(config, tuple, input, output); },
-                        );
-                    }
-                    _ => {}
-                }
+/// Connections the server has answered a SYN for that haven't finished their handshake.  Once
+/// there are too many, say from a flood of spoofed SYNs, new ones are answered with SYN cookies
+/// and get no state until they complete.
+#[derive(Debug)]
+struct PendingHandshakes {
+    started: HashMap<TCPTuple, Instant>,
+    threshold: usize,
+}
+
+impl PendingHandshakes {
+    fn new(threshold: usize) -> PendingHandshakes {
+        PendingHandshakes {
+            started: HashMap::new(),
+            threshold,
+        }
+    }
+
+    fn use_cookies(&mut self, now: Instant) -> bool {
+        let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT);
+        self.started.retain(|_, started| now.duration_since(*started) < timeout);
+        self.started.len() >= self.threshold
+    }
+}
+
+/// A live connection's TCB, told apart from earlier connections on the same tuple by its id
+#[derive(Debug)]
+struct Channel {
+    id: u64,
+    input: Sender<TCBInput>,
+}
+
+/// Hands datagrams arriving on the server's socket to the TCB for their connection, starting new
+/// connections for SYNs and reaping those whose TCB has closed
+#[derive(Debug)]
+pub struct Multiplexer {
+    config: Config,
+    channels: HashMap<TCPTuple, Channel>,
+    pending: PendingHandshakes,
+    dropped: DroppedDatagrams,
+    keepalive: Option<Keepalive>,
+    window_sizes: Option<(usize, usize)>,
+    next_id: u64,
+    closed_tx: Sender<(TCPTuple, u64)>,
+    closed_rx: Receiver<(TCPTuple, u64)>,
+}
+
+impl Multiplexer {
+    pub fn new(config: Config) -> Multiplexer {
+        let (closed_tx, closed_rx) = channel();
+        Multiplexer {
+            config,
+            channels: HashMap::new(),
+            pending: PendingHandshakes::new(SYN_COOKIE_THRESHOLD),
+            dropped: DroppedDatagrams::default(),
+            keepalive: Some(Keepalive::default()),
+            window_sizes: None,
+            next_id: 0,
+            closed_tx,
+            closed_rx,
+        }
+    }
+
+    /// How many handshakes may be pending before SYNs are answered with cookies
+    pub fn set_syn_cookie_threshold(&mut self, threshold: usize) {
+        self.pending = PendingHandshakes::new(threshold);
+    }
+
+    /// Keepalive for new connections, so clients that vanish don't hold on to their TCBs forever
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Send and receive window sizes for new connections, see `TCB::set_window_sizes`
+    pub fn set_window_sizes(&mut self, send: usize, recv: usize) {
+        self.window_sizes = Some((send, recv));
+    }
+
+    /// Datagrams thrown away so far because they didn't parse
+    pub fn dropped(&self) -> DroppedDatagrams {
+        self.dropped
+    }
+
+    /// Forgets connections whose TCB has closed
+    fn reap(&mut self) {
+        while let Ok((tuple, id)) = self.closed_rx.try_recv() {
+            // The tuple may already belong to a newer connection
+            if self.channels.get(&tuple).is_some_and(|chan| chan.id == id) {
+                self.channels.remove(&tuple);
+                self.pending.started.remove(&tuple);
             }
         }
-        Err(_) => return Err(()),
-    };
+    }
+
+    fn spawn_connection(
+        &mut self,
+        tuple: TCPTuple,
+        (mut tcb, input, output): (TCB, Sender<TCBInput>, TCBOutput),
+        seg: Segment,
+    ) {
+        println!("New connection! {:?}", tuple);
+        // Every echo is a small message answered straight away, Nagle would only stall it
+        tcb.set_nodelay(true);
+        tcb.set_keepalive(self.keepalive);
+        let id = self.next_id;
+        self.next_id += 1;
+        let udp_sender = input.clone();
+        udp_sender.send(TCBInput::Receive(seg)).unwrap();
+        self.channels.insert(
+            tuple,
+            Channel {
+                id,
+                input: udp_sender,
+            },
+        );
+
+        let config = self.config.clone();
+        let closed = self.closed_tx.clone();
+        std::thread::spawn(move || {
+            tcb.run_tcp();
+            drop(tcb);
+            let _ = closed.send((tuple, id));
+        });
+        std::thread::spawn(
+            move || { run_server_tcb(config, tuple, input, output); },
+        );
+    }
+
+    /// Handles one datagram, or returns after the socket's read timeout if none arrive so closed
+    /// connections still get reaped
+    pub fn receive(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.reap();
+        let mut buf = vec![0; (1 << 16) - 1];
+        let (amt, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let seg = match Segment::parse(&buf[..amt]) {
+            Ok(seg) => seg,
+            Err(e) => {
+                self.dropped.record(&e);
+                return Ok(());
+            }
+        };
+
+        let tuple = TCPTuple {
+            src: socket.local_addr()?,
+            dst: src, // Send replies to the sender
+        };
+        let mut valid_channel_found = false;
+        if let Entry::Occupied(entry) = self.channels.entry(tuple) {
+            let seg_copy = seg.clone();
+            if entry.into_mut().input.send(TCBInput::Receive(seg_copy)).is_ok() {
+                valid_channel_found = true;
+            }
+        }
+        if valid_channel_found {
+            if !seg.get_flag(Flag::SYN) {
+                self.pending.started.remove(&tuple);
+            }
+            return Ok(());
+        }
+        self.channels.remove(&tuple);
+
+        let window_sizes = self.window_sizes.unwrap_or((WINDOW_SIZE, WINDOW_SIZE));
+        if seg.get_flag(Flag::SYN) {
+            if self.pending.use_cookies(Instant::now()) {
+                let synack = TCBCore::syn_cookie_reply(&tuple, &seg, window_sizes.1);
+                socket.send_to(&synack.to_byte_vec(), tuple.dst)?;
+            } else {
+                self.pending.started.insert(tuple, Instant::now());
+                let mut connection = TCB::new(tuple, socket.try_clone()?);
+                connection.0.set_window_sizes(window_sizes.0, window_sizes.1);
+                self.spawn_connection(tuple, connection, seg);
+            }
+        } else if let Some(connection) =
+                   TCB::from_syn_cookie(tuple, socket.try_clone()?, &seg, window_sizes)
+        {
+            self.spawn_connection(tuple, connection, seg);
+        } else if let Some(rst) = TCBCore::reset_reply(&tuple, &seg) {
+            // Only a SYN may open a connection, anything else is for one we don't know about
+            socket.send_to(&rst.to_byte_vec(), tuple.dst)?;
+        }
 
-    return Ok(());
+        Ok(())
+    }
 }
 
-pub fn run_server(config: Config) -> Result<(), ()> {
+pub fn run_server(config: Config) -> io::Result<()> {
     println!("Starting Server...");
 
-    let mut channels: HashMap<TCPTuple, Sender<TCBInput>> = HashMap::new();
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.port)).unwrap();
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.port))?;
+    socket.set_read_timeout(Some(Duration::from_secs(REAP_INTERVAL)))?;
+    let mut multiplexer = Multiplexer::new(config);
 
-    'event_loop: loop {
-        multiplexed_receive(&config, &mut channels, &socket)?;
+    loop {
+        multiplexer.receive(&socket)?;
     }
 }
 
-pub fn run_client(config: ClientConfig) -> Result<(), ()> {
+pub fn run_client(config: ClientConfig) -> io::Result<()> {
     println!("Starting Client...");
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.src_port)).unwrap();
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.src_port))?;
     let tuple = TCPTuple {
-        src: socket.local_addr().unwrap(),
+        src: socket.local_addr()?,
         dst: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), config.dst_port),
     };
-    let (mut tcb, input, output) = TCB::new(tuple, socket.try_clone().unwrap());
+    let (mut tcb, input, output) = TCB::new(tuple, socket.try_clone()?);
+    tcb.set_nodelay(true);
     let tcb_thread = std::thread::spawn(move || tcb.run_tcp());
-    input.send(TCBInput::SendSyn);
+    input.send(TCBInput::SendSyn).unwrap();
 
     let seg_input = input.clone();
-    std::thread::spawn(move || 'socket_loop: loop {
+    let dropped = Arc::new(Mutex::new(DroppedDatagrams::default()));
+    let socket_dropped = dropped.clone();
+    std::thread::spawn(move || loop {
         let mut buf = vec![0; (1 << 16) - 1];
-        match socket.recv_from(&mut buf) {
-            Ok((amt, src)) => {
-                buf.truncate(amt);
-                let seg = Segment::from_buf(buf);
-                if seg.validate() {
-                    seg_input.send(TCBInput::Receive(seg));
+        if let Ok((amt, _)) = socket.recv_from(&mut buf) {
+            match Segment::parse(&buf[..amt]) {
+                Ok(seg) => {
+                    if seg_input.send(TCBInput::Receive(seg)).is_err() {
+                        break;
+                    }
                 }
+                Err(e) => socket_dropped.lock().unwrap().record(&e),
             }
-            Err(_) => {}
         }
     });
 
-    let file_contents = recv_str(&output).unwrap();
-    // println!("Current File Contents {}", file_contents);
+    let _file_contents = recv_str(&output)?;
+    // println!("Current File Contents {}", _file_contents);
     send_str(&input, String::from("\n lol cool story bro")).unwrap();
 
-    let echo1 = recv_str(&output).unwrap();
-    // println!("Echo 1 {}", echo1);
+    let _echo1 = recv_str(&output)?;
+    // println!("Echo 1 {}", _echo1);
 
-    input.send(TCBInput::Close);
-    tcb_thread.join();
+    input.send(TCBInput::Close).unwrap();
+    tcb_thread.join().unwrap();
 
+    let dropped = *dropped.lock().unwrap();
+    if dropped.total() > 0 {
+        println!("Dropped {} bad datagrams: {:?}", dropped.total(), dropped);
+    }
     println!("Ending Client");
 
     Ok(())
@@ -191,6 +351,7 @@ pub fn run_client(config: ClientConfig) -> Result<(), ()> {
 mod tests {
     use super::*;
     use std::path::{Path, PathBuf};
+    use std::time::Duration;
 
     #[test]
     fn get_file_test() {
@@ -199,7 +360,7 @@ mod tests {
             dst: "127.0.0.1:12345".parse().unwrap(),
         };
         let folderpath = Path::new("./");
-        let mut file = get_file(&tuple, &folderpath).unwrap();
+        let mut file = get_file(&tuple, folderpath).unwrap();
         let mut s = String::new();
         file.read_to_string(&mut s).unwrap();
         println!("Got file of length {}", s.len());
@@ -208,7 +369,164 @@ mod tests {
         std::fs::remove_file(filepath).unwrap();
     }
 
-    // const SCRIPT: &'static str = "Did you ever hear the tragedy of Darth Plagueis The Wise? I thought not. It’s not a story the Jedi would tell you. It’s a Sith legend. Darth Plagueis was a Dark Lord of the Sith, so powerful and so wise he could use the Force to influence the midichlorians to create life… He had such a knowledge of the dark side that he could even keep the ones he cared about from dying. The dark side of the Force is a pathway to many abilities some consider to be unnatural. He became so powerful… the only thing he was afraid of was losing his power, which eventually, of course, he did. Unfortunately, he taught his apprentice everything he knew, then his apprentice killed him in his sleep. Ironic. He could save others from death, but not himself.";
+    #[test]
+    fn unknown_tuple_gets_reset() {
+        let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            port: server_sock.local_addr().unwrap().port(),
+            filepath: PathBuf::from("./"),
+        };
+        let mut ack = Segment::new(client_sock.local_addr().unwrap().port(), config.port);
+        let mut multiplexer = Multiplexer::new(config);
+        ack.set_flag(Flag::ACK);
+        ack.set_ack_num(1234);
+        client_sock
+            .send_to(&ack.to_byte_vec(), server_sock.local_addr().unwrap())
+            .unwrap();
+        multiplexer.receive(&server_sock).unwrap();
+        assert!(multiplexer.channels.is_empty());
+
+        let mut buf = vec![0; (1 << 16) - 1];
+        let (amt, _) = client_sock.recv_from(&mut buf).unwrap();
+        let rst = Segment::parse(&buf[..amt]).unwrap();
+        assert!(rst.get_flag(Flag::RST));
+        assert_eq!(rst.seq_num(), 1234);
+    }
+
+    #[test]
+    fn bad_datagrams_are_counted() {
+        let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server_sock.local_addr().unwrap();
+        let config = Config {
+            port: server_addr.port(),
+            filepath: std::env::temp_dir(),
+        };
+        let mut multiplexer = Multiplexer::new(config);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[1, 2, 3], server_addr).unwrap();
+        multiplexer.receive(&server_sock).unwrap();
+        let mut syn = Segment::new(client.local_addr().unwrap().port(), server_addr.port());
+        syn.set_flag(Flag::SYN);
+        let mut bytes = syn.to_byte_vec();
+        bytes[8] ^= 0xFF;
+        client.send_to(&bytes, server_addr).unwrap();
+        multiplexer.receive(&server_sock).unwrap();
+
+        assert!(multiplexer.channels.is_empty());
+        assert_eq!(
+            multiplexer.dropped(),
+            DroppedDatagrams {
+                truncated: 1,
+                bad_checksum: 1,
+                ..DroppedDatagrams::default()
+            }
+        );
+    }
+
+    #[test]
+    fn syn_flood_switches_to_cookies() {
+        let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server_sock.local_addr().unwrap();
+        let config = Config {
+            port: server_addr.port(),
+            filepath: std::env::temp_dir(),
+        };
+        let mut multiplexer = Multiplexer::new(config);
+        multiplexer.set_syn_cookie_threshold(1);
+
+        // The first handshake gets a TCB straight away
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_syn(&first, server_addr, 100);
+        multiplexer.receive(&server_sock).unwrap();
+        assert_eq!(multiplexer.channels.len(), 1);
+
+        // That's the limit, the next one only gets a cookie
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_syn(&second, server_addr, 200);
+        multiplexer.receive(&server_sock).unwrap();
+        assert_eq!(multiplexer.channels.len(), 1);
+        let synack = sock_recv(&second);
+        assert!(synack.get_flag(Flag::SYN) && synack.get_flag(Flag::ACK));
+        assert_eq!(synack.ack_num(), 201);
+
+        // Returning the cookie creates the connection, which then sends the file as usual
+        send_ack(&second, server_addr, 201, synack.seq_num().wrapping_add(1));
+        multiplexer.receive(&server_sock).unwrap();
+        assert_eq!(multiplexer.channels.len(), 2);
+        let data = sock_recv(&second);
+        assert_eq!(data.seq_num(), synack.seq_num().wrapping_add(1));
+        assert!(!data.payload().is_empty());
+    }
+
+    #[test]
+    fn vanished_client_is_reaped() {
+        let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server_sock.local_addr().unwrap();
+        let config = Config {
+            port: server_addr.port(),
+            filepath: std::env::temp_dir(),
+        };
+        let mut multiplexer = Multiplexer::new(config);
+        multiplexer.set_keepalive(Some(Keepalive {
+            idle: Duration::from_millis(50),
+            interval: Duration::from_millis(10),
+            probes: 1,
+        }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_syn(&client, server_addr, 100);
+        multiplexer.receive(&server_sock).unwrap();
+        let synack = sock_recv(&client);
+        send_ack(&client, server_addr, 101, synack.seq_num().wrapping_add(1));
+        multiplexer.receive(&server_sock).unwrap();
+        let data = sock_recv(&client);
+        let data_end = data.seq_num().wrapping_add(data.payload().len() as u32);
+        send_ack(&client, server_addr, 101, data_end);
+        multiplexer.receive(&server_sock).unwrap();
+        assert_eq!(multiplexer.channels.len(), 1);
+
+        // Then the client goes quiet and never answers the keepalive probe
+        let probe = sock_recv(&client);
+        assert_eq!(probe.seq_num(), data_end.wrapping_sub(1));
+        server_sock
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        for _ in 0..200 {
+            multiplexer.receive(&server_sock).unwrap();
+            if multiplexer.channels.is_empty() {
+                return;
+            }
+        }
+        panic!("Connection never reaped");
+    }
+
+    fn sock_recv(sock: &UdpSocket) -> Segment {
+        let mut buf = vec![0; (1 << 16) - 1];
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (amt, _) = sock.recv_from(&mut buf).unwrap();
+        Segment::parse(&buf[..amt]).unwrap()
+    }
+
+    fn send_syn(sock: &UdpSocket, server: SocketAddr, isn: u32) {
+        let mut syn = Segment::new(sock.local_addr().unwrap().port(), server.port());
+        syn.set_flag(Flag::SYN);
+        syn.set_seq(isn);
+        syn.set_window(1000);
+        syn.set_options(vec![SegmentOption::MaxSegmentSize(1200)]);
+        sock.send_to(&syn.to_byte_vec(), server).unwrap();
+    }
+
+    fn send_ack(sock: &UdpSocket, server: SocketAddr, seq: u32, ack_num: u32) {
+        let mut ack = Segment::new(sock.local_addr().unwrap().port(), server.port());
+        ack.set_flag(Flag::ACK);
+        ack.set_seq(seq);
+        ack.set_ack_num(ack_num);
+        ack.set_window(1000);
+        sock.send_to(&ack.to_byte_vec(), server).unwrap();
+    }
+
+    // const SCRIPT: &str = "Did you ever hear the tragedy of Darth Plagueis The Wise? I thought not. It’s not a story the Jedi would tell you. It’s a Sith legend. Darth Plagueis was a Dark Lord of the Sith, so powerful and so wise he could use the Force to influence the midichlorians to create life… He had such a knowledge of the dark side that he could even keep the ones he cared about from dying. The dark side of the Force is a pathway to many abilities some consider to be unnatural. He became so powerful… the only thing he was afraid of was losing his power, which eventually, of course, he did. Unfortunately, he taught his apprentice everything he knew, then his apprentice killed him in his sleep. Ironic. He could save others from death, but not himself.";
 
     #[test]
     fn transfer_data() {
@@ -246,11 +564,17 @@ mod tests {
 
     #[test]
     fn file_echo_test() {
+        let (client_done_tx, client_done_rx) = channel();
         let ((server_input, server_output, server_sock),
              (client_input, client_output, client_sock)) =
             tcp::tests::run_e2e_pair(
                 |mut server_tcb: TCB| server_tcb.run_tcp(),
-                |mut client_tcb: TCB| client_tcb.run_tcp(),
+                move |mut client_tcb: TCB| {
+                    client_tcb.set_time_wait(Duration::from_millis(50));
+                    client_tcb.run_tcp();
+                    drop(client_tcb);
+                    client_done_tx.send(()).unwrap();
+                },
             );
 
         let (server_tuple, _) = get_tuples_from_socks(&server_sock, &client_sock);
@@ -260,31 +584,25 @@ mod tests {
             filepath: PathBuf::from("./"),
         };
 
-        let _server = std::thread::spawn(move || {
-            run_server_tcb(server_config, server_tuple, server_input, server_output);
-        });
-
         let filepath = Path::new("./");
         let filepath = filepath.join(tuple_to_filename(&server_tuple));
         let mut file = File::create(filepath.clone()).unwrap();
         let initial_contents = String::from(
             "Did you ever hear the tragedy of Darth Plagueis the wise?\n",
         );
-        file.write_all(&initial_contents.as_bytes()).unwrap();
+        file.write_all(initial_contents.as_bytes()).unwrap();
         file.flush().unwrap();
         file.sync_data().unwrap();
         drop(file);
 
+        // The server reads the file as soon as it starts, so it must be written first
+        let _server = std::thread::spawn(move || {
+            run_server_tcb(server_config, server_tuple, server_input, server_output);
+        });
+
         client_input.send(TCBInput::SendSyn).unwrap();
         let file_contents = recv_str(&client_output).unwrap();
-
-        // NOTE: Sometimes the write doesn't actually succeed even though both flush and sync_data
-        //       are called, so this assertion might fail...  just re-run the test if it does
-        assert_eq!(
-            file_contents,
-            initial_contents,
-            "\n\x1b[35m NOTE: This test may not have actually failed, try re-running \x1b[0m"
-        );
+        assert_eq!(file_contents, initial_contents);
 
         let response = String::from("It's not a story the jedi would tell you");
         send_str(&client_input, response.clone()).unwrap();
@@ -295,6 +613,7 @@ mod tests {
         client_input.send(TCBInput::Close).unwrap();
         _server.join().unwrap();
 
+        client_done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
         assert!(client_input.send(TCBInput::Close).is_err());
 
         std::fs::remove_file(filepath.clone()).unwrap();
@@ -326,7 +645,7 @@ mod tests {
     }
 
 
-    const SCRIPT: &'static str = "\
+    const SCRIPT: &str = "\
 Call me Ishmael. Some years ago--never mind how long precisely--having little or no money in my purse, and nothing particular to interest me on shore, I thought I would sail about a little and see the watery part of the world. It is a way I have of driving off the spleen and regulating the circulation. Whenever I find myself growing grim about the mouth; whenever it is a damp, drizzly November in my soul; whenever I find myself involuntarily pausing before coffin warehouses, and bringing up the rear of every funeral I meet; and especially whenever my hypos get such an upper hand of me, that it requires a strong moral principle to prevent me from deliberately stepping into the street, and methodically knocking people's hats off--then, I account it high time to get to sea as soon as I can. This is my substitute for pistol and ball. With a philosophical flourish Cato throws himself upon his sword; I quietly take to the ship. There is nothing surprising in this. If they but knew it, almost all men in their degree, some time or other, cherish very nearly the same feelings towards the ocean with me.
 
 There now is your insular city of the Manhattoes, belted round by wharves as Indian isles by coral reefs--commerce surrounds it with her surf. Right and left, the streets take you waterward. Its extreme downtown is the battery, where that noble mole is washed by waves, and cooled by breezes, which a few hours previous were out of sight of land. Look at the crowds of water-gazers there.
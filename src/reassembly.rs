@@ -0,0 +1,191 @@
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+
+/// Received data waiting to be delivered, kept as runs of payload rather than byte by byte.
+/// Runs never overlap or touch, overlapping and adjacent segments are merged as they arrive.
+///
+/// Positions are sequence numbers, always taken relative to `base`, the next byte the
+/// application is owed, so wrapping around `u32::MAX` needs no special handling.
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    runs: VecDeque<(u32, Vec<u8>)>, // Start and data, in sequence order
+    len: usize,
+}
+
+/// Where a run sits relative to `base`, as a half open range of offsets
+fn span(base: u32, &(start, ref data): &(u32, Vec<u8>)) -> (usize, usize) {
+    let offset = start.wrapping_sub(base) as usize;
+    (offset, offset + data.len())
+}
+
+impl Reassembly {
+    pub fn new() -> Reassembly {
+        Reassembly::default()
+    }
+
+    /// Stores `data`, which starts at sequence number `seq`.  Anything before `base` has already
+    /// been delivered and anything past the `window` bytes after it doesn't fit, both are
+    /// dropped.  Returns how many bytes weren't already held.
+    pub fn insert(&mut self, base: u32, window: usize, seq: u32, data: &[u8]) -> usize {
+        let offset = seq.wrapping_sub(base) as i32 as i64;
+        let skip = max(-offset, 0) as usize;
+        if skip >= data.len() {
+            return 0;
+        }
+        let start = max(offset, 0) as usize;
+        let end = min(start + data.len() - skip, window);
+        if start >= end {
+            return 0;
+        }
+        let data = &data[skip..skip + end - start];
+
+        // Every run overlapping or touching the new data gets merged with it
+        let first = self.runs
+            .iter()
+            .position(|run| span(base, run).1 >= start)
+            .unwrap_or(self.runs.len());
+        let last = self.runs
+            .iter()
+            .skip(first)
+            .position(|run| span(base, run).0 > end)
+            .map_or(self.runs.len(), |i| first + i);
+        if first == last {
+            self.runs.insert(
+                first,
+                (base.wrapping_add(start as u32), data.to_vec()),
+            );
+            self.len += data.len();
+            return data.len();
+        }
+
+        let merged_start = min(start, span(base, &self.runs[first]).0);
+        let merged_end = max(end, span(base, &self.runs[last - 1]).1);
+        let mut merged = vec![0; merged_end - merged_start];
+        merged[start - merged_start..end - merged_start].copy_from_slice(data);
+        let mut held = 0;
+        for run in self.runs.drain(first..last) {
+            let (run_start, run_end) = span(base, &run);
+            merged[run_start - merged_start..run_end - merged_start].copy_from_slice(&run.1);
+            held += run.1.len();
+        }
+        self.runs.insert(
+            first,
+            (base.wrapping_add(merged_start as u32), merged),
+        );
+        let added = merged_end - merged_start - held;
+        self.len += added;
+        added
+    }
+
+    /// Takes out everything that has arrived in order from `base` on
+    pub fn pop(&mut self, base: u32) -> Option<Vec<u8>> {
+        if self.runs.front()?.0 != base {
+            return None;
+        }
+        let (_, data) = self.runs.pop_front()?;
+        self.len -= data.len();
+        Some(data)
+    }
+
+    /// Start and end sequence numbers of each run held, in order
+    pub fn ranges(&self) -> Vec<(u32, u32)> {
+        self.runs
+            .iter()
+            .map(|&(start, ref data)| (start, start.wrapping_add(data.len() as u32)))
+            .collect()
+    }
+
+    /// Bytes held
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut buf = Reassembly::new();
+        assert_eq!(buf.insert(100, 1000, 100, &[1, 2, 3]), 3);
+        assert_eq!(buf.pop(100), Some(vec![1, 2, 3]));
+        assert_eq!(buf.pop(103), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn fills_holes() {
+        let mut buf = Reassembly::new();
+        assert_eq!(buf.insert(0, 1000, 20, &[3; 10]), 10);
+        assert_eq!(buf.insert(0, 1000, 40, &[5; 10]), 10);
+        assert_eq!(buf.ranges(), vec![(20, 30), (40, 50)]);
+        assert_eq!(buf.pop(0), None);
+
+        // Touching runs merge, overlapping bytes are only counted once
+        assert_eq!(buf.insert(0, 1000, 25, &[4; 15]), 10);
+        assert_eq!(buf.ranges(), vec![(20, 50)]);
+        assert_eq!(buf.len(), 30);
+        assert_eq!(buf.insert(0, 1000, 0, &[1; 20]), 20);
+
+        let mut expected = vec![1; 20];
+        expected.extend(vec![3; 10]);
+        expected.extend(vec![4; 10]);
+        expected.extend(vec![5; 10]);
+        assert_eq!(buf.pop(0), Some(expected));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn spanning_several_runs() {
+        let mut buf = Reassembly::new();
+        buf.insert(0, 1000, 10, &[1; 5]);
+        buf.insert(0, 1000, 20, &[2; 5]);
+        buf.insert(0, 1000, 30, &[3; 5]);
+        buf.insert(0, 1000, 50, &[4; 5]);
+        assert_eq!(buf.insert(0, 1000, 5, &[9; 30]), 15);
+        assert_eq!(buf.ranges(), vec![(5, 35), (50, 55)]);
+
+        let mut expected = vec![9; 5];
+        expected.extend(vec![1; 5]);
+        expected.extend(vec![9; 5]);
+        expected.extend(vec![2; 5]);
+        expected.extend(vec![9; 5]);
+        expected.extend(vec![3; 5]);
+        buf.insert(0, 1000, 0, &[0; 5]);
+        let mut delivered = vec![0; 5];
+        delivered.extend(expected);
+        assert_eq!(buf.pop(0), Some(delivered));
+        assert_eq!(buf.len(), 5);
+    }
+
+    #[test]
+    fn trims_to_window() {
+        let mut buf = Reassembly::new();
+        // Already delivered, partly or entirely
+        assert_eq!(buf.insert(100, 50, 90, &[1; 5]), 0);
+        assert_eq!(buf.insert(100, 50, 95, &[2; 10]), 5);
+        assert_eq!(buf.ranges(), vec![(100, 105)]);
+        // Beyond the window
+        assert_eq!(buf.insert(100, 50, 150, &[3; 5]), 0);
+        assert_eq!(buf.insert(100, 50, 140, &[4; 20]), 10);
+        assert_eq!(buf.ranges(), vec![(100, 105), (140, 150)]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let base = u32::MAX - 4;
+        let mut buf = Reassembly::new();
+        assert_eq!(buf.insert(base, 1000, 3, &[2; 5]), 5);
+        assert_eq!(buf.insert(base, 1000, base, &[1; 8]), 8);
+        assert_eq!(buf.ranges(), vec![(base, 8)]);
+        let mut expected = vec![1; 8];
+        expected.extend(vec![2; 5]);
+        assert_eq!(buf.pop(base), Some(expected));
+    }
+}
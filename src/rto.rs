@@ -0,0 +1,126 @@
+use std::cmp::{min, max};
+use std::time::Duration;
+
+pub const INITIAL_RTO_MS: u64 = 1000;
+pub const MIN_RTO_MS: u64 = 200;
+pub const MAX_RTO_MS: u64 = 60_000;
+
+/// Round trip time estimation and retransmission timeout from RFC 6298
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> RttEstimator {
+        RttEstimator::new(
+            Duration::from_millis(MIN_RTO_MS),
+            Duration::from_millis(MAX_RTO_MS),
+        )
+    }
+}
+
+impl RttEstimator {
+    pub fn new(min_rto: Duration, max_rto: Duration) -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: max(min_rto, min(max_rto, Duration::from_millis(INITIAL_RTO_MS))),
+            min_rto,
+            max_rto,
+        }
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Folds in a measurement.  Per Karn's algorithm it must come from a segment that was only
+    /// sent once, otherwise there's no telling which transmission the ACK was for.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let err = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + err) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        // Any backoff is dropped now that there's a fresh measurement
+        let rto = self.srtt.unwrap() + max(Duration::from_millis(1), self.rttvar * 4);
+        self.rto = max(self.min_rto, min(self.max_rto, rto));
+    }
+
+    /// Doubles the timeout after it expires without an ACK
+    pub fn back_off(&mut self) {
+        self.rto = min(self.max_rto, self.rto * 2);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn initial_rto() {
+        let rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(), ms(INITIAL_RTO_MS));
+        assert_eq!(rtt.srtt(), None);
+    }
+
+    #[test]
+    fn converges_on_samples() {
+        let mut rtt = RttEstimator::new(ms(1), ms(MAX_RTO_MS));
+        rtt.sample(ms(100));
+        assert_eq!(rtt.srtt(), Some(ms(100)));
+        assert_eq!(rtt.rto(), ms(300));
+
+        for _ in 0..50 {
+            rtt.sample(ms(40));
+        }
+        let srtt = rtt.srtt().unwrap();
+        assert!(srtt > ms(39) && srtt < ms(41), "{:?}", srtt);
+        assert!(rtt.rto() < ms(45), "{:?}", rtt.rto());
+    }
+
+    #[test]
+    fn bounds() {
+        let mut rtt = RttEstimator::default();
+        rtt.sample(ms(1));
+        assert_eq!(rtt.rto(), ms(MIN_RTO_MS));
+
+        rtt.sample(ms(200_000));
+        assert_eq!(rtt.rto(), ms(MAX_RTO_MS));
+    }
+
+    #[test]
+    fn back_off() {
+        let mut rtt = RttEstimator::default();
+        rtt.back_off();
+        assert_eq!(rtt.rto(), ms(2 * INITIAL_RTO_MS));
+        for _ in 0..10 {
+            rtt.back_off();
+        }
+        assert_eq!(rtt.rto(), ms(MAX_RTO_MS));
+
+        // A new measurement clears the backoff
+        rtt.sample(ms(100));
+        assert_eq!(rtt.rto(), ms(300));
+    }
+}
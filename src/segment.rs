@@ -9,17 +9,19 @@ pub struct Segment {
     ack_num: u32,
     flags: u16,
     checksum: u16,
+    window: Option<u16>,
+    options: Vec<SegmentOption>,
     payload: Box<[u8]>,
 }
 
-use std::fmt::{Binary, Formatter, Error};
+use std::fmt::{Binary, Display, Formatter, Error};
 
 impl Binary for Segment {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         let bytes = self.to_byte_vec();
         for (i, b) in bytes.iter().enumerate() {
             if i % 2 == 0 {
-                write!(f, "\n")?;
+                writeln!(f)?;
             }
             write!(f, "{:01$b} ", b, 8)?;
         }
@@ -27,12 +29,188 @@ impl Binary for Segment {
     }
 }
 
+pub const HEADER_SIZE: usize = 20;
+
+// The low byte of the flags field was unused in the original TPP header, so it now holds the
+// header length in 32 bit words.  Legacy peers leave it zero, meaning a bare 20 byte header.
+const HEADER_WORDS_MASK: u16 = 0x00FF;
+
+/// TCP style options carried between the fixed header and the payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentOption {
+    NoOp,
+    MaxSegmentSize(u16),
+    WindowScale(u8),
+    SackPermitted,
+    /// Blocks of data received beyond the cumulative ACK, each from its left edge up to but not
+    /// including its right edge (RFC 2018)
+    Sack(Vec<(u32, u32)>),
+    Timestamps { val: u32, ecr: u32 },
+    Unknown(u8, Vec<u8>),
+}
+
+const OPT_END: u8 = 0;
+const OPT_NOOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+const OPT_WINDOW_SCALE: u8 = 3;
+const OPT_SACK_PERMITTED: u8 = 4;
+const OPT_SACK: u8 = 5;
+const OPT_TIMESTAMPS: u8 = 8;
+// Not a TCP option, TPP has no window field in its fixed header so the advertised window rides
+// in the options area instead.  It takes 253, which RFC 4727 sets aside for experiments, as every
+// kind below that is already assigned by IANA and a parser that knows them would misread it.
+const OPT_WINDOW: u8 = 253;
+
+impl SegmentOption {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            SegmentOption::NoOp => out.push(OPT_NOOP),
+            SegmentOption::MaxSegmentSize(mss) => {
+                out.extend(&[OPT_MSS, 4]);
+                out.extend(u16_to_u8(mss));
+            }
+            SegmentOption::WindowScale(shift) => out.extend(&[OPT_WINDOW_SCALE, 3, shift]),
+            SegmentOption::SackPermitted => out.extend(&[OPT_SACK_PERMITTED, 2]),
+            SegmentOption::Sack(ref blocks) => {
+                out.extend(&[OPT_SACK, (blocks.len() * 8 + 2) as u8]);
+                for &(left, right) in blocks {
+                    out.extend(u32_to_u8(left));
+                    out.extend(u32_to_u8(right));
+                }
+            }
+            SegmentOption::Timestamps { val, ecr } => {
+                out.extend(&[OPT_TIMESTAMPS, 10]);
+                out.extend(u32_to_u8(val));
+                out.extend(u32_to_u8(ecr));
+            }
+            SegmentOption::Unknown(kind, ref data) => {
+                out.extend(&[kind, (data.len() + 2) as u8]);
+                out.extend(data);
+            }
+        }
+    }
+
+    /// Decodes an options area, pulling the advertised window out from the other options
+    fn decode_all(mut buf: &[u8]) -> Result<(Vec<SegmentOption>, Option<u16>), SegmentError> {
+        let mut options = vec![];
+        let mut window = None;
+        while let Some(&kind) = buf.first() {
+            match kind {
+                OPT_END => break,
+                OPT_NOOP => {
+                    options.push(SegmentOption::NoOp);
+                    buf = &buf[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            if buf.len() < 2 || buf[1] < 2 || buf[1] as usize > buf.len() {
+                return Err(SegmentError::BadOption(kind));
+            }
+            let (opt, rest) = buf.split_at(buf[1] as usize);
+            let data = &opt[2..];
+            buf = rest;
+            if kind == OPT_WINDOW {
+                if data.len() != 2 {
+                    return Err(SegmentError::BadOption(kind));
+                }
+                window = Some(buf_to_u16(data));
+                continue;
+            }
+            options.push(match (kind, data.len()) {
+                (OPT_MSS, 2) => SegmentOption::MaxSegmentSize(buf_to_u16(data)),
+                (OPT_WINDOW_SCALE, 1) => SegmentOption::WindowScale(data[0]),
+                (OPT_SACK_PERMITTED, 0) => SegmentOption::SackPermitted,
+                (OPT_SACK, len) if len > 0 && len % 8 == 0 => SegmentOption::Sack(
+                    data.chunks(8)
+                        .map(|block| (buf_to_u32(&block[0..4]), buf_to_u32(&block[4..8])))
+                        .collect(),
+                ),
+                (OPT_TIMESTAMPS, 8) => SegmentOption::Timestamps {
+                    val: buf_to_u32(&data[0..4]),
+                    ecr: buf_to_u32(&data[4..8]),
+                },
+                (OPT_MSS, _) |
+                (OPT_WINDOW_SCALE, _) |
+                (OPT_SACK_PERMITTED, _) |
+                (OPT_SACK, _) |
+                (OPT_TIMESTAMPS, _) => return Err(SegmentError::BadOption(kind)),
+                _ => SegmentOption::Unknown(kind, data.to_vec()),
+            });
+        }
+        Ok((options, window))
+    }
+}
+
+/// Reasons a datagram could not be interpreted as a `Segment`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SegmentError {
+    /// Fewer bytes than a full header
+    Truncated(usize),
+    /// The header's segment size doesn't match the datagram length
+    SizeMismatch { declared: u32, actual: usize },
+    BadChecksum,
+    /// The header length in the flags field is too small or runs past the datagram
+    BadHeaderLength(u8),
+    /// An option of the given kind has a length that doesn't fit
+    BadOption(u8),
+}
+
+impl Display for SegmentError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            SegmentError::Truncated(len) => {
+                write!(f, "truncated header ({} of {} bytes)", len, HEADER_SIZE)
+            }
+            SegmentError::SizeMismatch { declared, actual } => {
+                write!(
+                    f,
+                    "segment size {} doesn't match datagram length {}",
+                    declared,
+                    actual
+                )
+            }
+            SegmentError::BadChecksum => write!(f, "bad checksum"),
+            SegmentError::BadHeaderLength(words) => {
+                write!(f, "bad header length of {} words", words)
+            }
+            SegmentError::BadOption(kind) => write!(f, "malformed option of kind {}", kind),
+        }
+    }
+}
+
+impl ::std::error::Error for SegmentError {}
+
+/// Tally of received datagrams that were thrown away instead of being parsed
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DroppedDatagrams {
+    pub truncated: u64,
+    pub size_mismatch: u64,
+    pub bad_checksum: u64,
+    pub bad_options: u64,
+}
 
+impl DroppedDatagrams {
+    pub fn record(&mut self, err: &SegmentError) {
+        match *err {
+            SegmentError::Truncated(_) => self.truncated += 1,
+            SegmentError::SizeMismatch { .. } => self.size_mismatch += 1,
+            SegmentError::BadChecksum => self.bad_checksum += 1,
+            SegmentError::BadHeaderLength(_) |
+            SegmentError::BadOption(_) => self.bad_options += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.truncated + self.size_mismatch + self.bad_checksum + self.bad_options
+    }
+}
 
 pub enum Flag {
     ACK,
     SYN,
     FIN,
+    RST,
 }
 
 impl Segment {
@@ -66,33 +244,153 @@ impl Segment {
         Vec::from(&*self.payload)
     }
 
+    /// Sequence space taken up by the segment, the SYN and FIN flags each count as one
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.get_flag(Flag::SYN) {
+            len += 1;
+        }
+        if self.get_flag(Flag::FIN) {
+            len += 1;
+        }
+        len
+    }
+
     pub fn new(src_port: u16, dst_port: u16) -> Segment {
         let mut base = Segment {
-            src_port: src_port,
-            dst_port: dst_port,
-            seg_size: 20,
+            src_port,
+            dst_port,
+            seg_size: HEADER_SIZE as u32,
             seq_num: 0,
             ack_num: 0,
             flags: 0,
             checksum: 0,
+            window: None,
+            options: vec![],
             payload: Box::new([]),
         };
         base.checksum = base.generate_checksum();
         base
     }
 
-    pub fn from_buf(buf: Vec<u8>) -> Segment {
-        assert!(buf.len() >= 20);
-        Segment {
+    pub fn parse(buf: &[u8]) -> Result<Segment, SegmentError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(SegmentError::Truncated(buf.len()));
+        }
+        let seg_size = buf_to_u32(&buf[4..8]);
+        if seg_size as usize != buf.len() {
+            return Err(SegmentError::SizeMismatch {
+                declared: seg_size,
+                actual: buf.len(),
+            });
+        }
+        // Options may be padded differently than we would encode them, so check the raw bytes
+        if ones_complement_sum(&mut buf.to_vec()) != 0xFFFF {
+            return Err(SegmentError::BadChecksum);
+        }
+
+        let flags = buf_to_u16(&buf[16..18]);
+        let header_words = (flags & HEADER_WORDS_MASK) as u8;
+        let header_len = match header_words {
+            0 => HEADER_SIZE,
+            words if (words as usize) * 4 < HEADER_SIZE => {
+                return Err(SegmentError::BadHeaderLength(words))
+            }
+            words => words as usize * 4,
+        };
+        if header_len > buf.len() {
+            return Err(SegmentError::BadHeaderLength(header_words));
+        }
+
+        let (options, window) = SegmentOption::decode_all(&buf[HEADER_SIZE..header_len])?;
+        Ok(Segment {
             src_port: buf_to_u16(&buf[0..2]),
             dst_port: buf_to_u16(&buf[2..4]),
-            seg_size: buf_to_u32(&buf[4..8]),
+            seg_size,
             seq_num: buf_to_u32(&buf[8..12]),
             ack_num: buf_to_u32(&buf[12..16]),
-            flags: buf_to_u16(&buf[16..18]),
+            flags,
             checksum: buf_to_u16(&buf[18..20]),
-            payload: Vec::from(&buf[20..]).into_boxed_slice(),
+            window,
+            options,
+            payload: Vec::from(&buf[header_len..]).into_boxed_slice(),
+        })
+    }
+
+    pub fn options(&self) -> &[SegmentOption] {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: Vec<SegmentOption>) {
+        self.options = options;
+        self.update_header_len();
+    }
+
+    /// Receive window the sender advertised, `None` for legacy peers which never send one
+    pub fn window(&self) -> Option<u16> {
+        self.window
+    }
+
+    /// SACK blocks the sender reported, empty if it sent none
+    pub fn sack_blocks(&self) -> &[(u32, u32)] {
+        for opt in &self.options {
+            if let SegmentOption::Sack(ref blocks) = *opt {
+                return blocks;
+            }
         }
+        &[]
+    }
+
+    /// The sender's timestamp and the one it echoes back (RFC 7323), if it sent any
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.options.iter().filter_map(|opt| match *opt {
+            SegmentOption::Timestamps { val, ecr } => Some((val, ecr)),
+            _ => None,
+        }).next()
+    }
+
+    /// Sets the timestamps option, replacing any already there
+    pub fn set_timestamps(&mut self, val: u32, ecr: u32) {
+        self.options.retain(|opt| !matches!(*opt, SegmentOption::Timestamps { .. }));
+        self.options.push(SegmentOption::Timestamps { val, ecr });
+        self.update_header_len();
+    }
+
+    pub fn set_window(&mut self, window: u16) {
+        self.window = Some(window);
+        self.update_header_len();
+    }
+
+    fn update_header_len(&mut self) {
+        let header_len = self.header_len();
+        let header_words = if header_len == HEADER_SIZE {
+            0
+        } else {
+            (header_len / 4) as u16
+        };
+        self.flags = (self.flags & !HEADER_WORDS_MASK) | header_words;
+        self.seg_size = (header_len + self.payload.len()) as u32;
+        self.checksum = self.generate_checksum();
+    }
+
+    pub fn header_len(&self) -> usize {
+        HEADER_SIZE + self.encode_options().len()
+    }
+
+    /// Encoded options, padded out to a whole number of 32 bit words
+    fn encode_options(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        if let Some(window) = self.window {
+            bytes.extend(&[OPT_WINDOW, 4]);
+            bytes.extend(u16_to_u8(window));
+        }
+        for opt in &self.options {
+            opt.encode(&mut bytes);
+        }
+        while bytes.len() % 4 != 0 {
+            bytes.push(OPT_END);
+        }
+        bytes
     }
 
     pub fn set_flag(&mut self, flag: Flag) {
@@ -101,6 +399,7 @@ impl Segment {
                 Flag::SYN => 15,
                 Flag::ACK => 14,
                 Flag::FIN => 13,
+                Flag::RST => 12,
             };
         self.checksum = self.generate_checksum();
     }
@@ -112,6 +411,7 @@ impl Segment {
                 Flag::SYN => 15,
                 Flag::ACK => 14,
                 Flag::FIN => 13,
+                Flag::RST => 12,
             };
         self.flags = !flipped;
         self.checksum = self.generate_checksum();
@@ -122,11 +422,12 @@ impl Segment {
             Flag::SYN => self.flags & 1 << 15 > 0,
             Flag::ACK => self.flags & 1 << 14 > 0,
             Flag::FIN => self.flags & 1 << 13 > 0,
+            Flag::RST => self.flags & 1 << 12 > 0,
         }
     }
 
     pub fn set_data(&mut self, data: Vec<u8>) {
-        self.seg_size = 20 + data.len() as u32;
+        self.seg_size = (self.header_len() + data.len()) as u32;
         self.payload = data.into_boxed_slice();
         self.checksum = self.generate_checksum();
     }
@@ -139,11 +440,43 @@ impl Segment {
         set.extend(u32_to_u8(self.ack_num));
         set.extend(u16_to_u8(self.flags));
         set.extend(u16_to_u8(self.checksum));
-        set.extend(self.payload.clone().iter());
+        set.extend(self.encode_options());
+        set.extend(self.payload.iter());
 
         set
     }
 
+    /// Encodes the segment carrying `payload` after its own, which is how data goes out straight
+    /// from a send buffer without being copied into a segment first.  The payload comes in parts
+    /// so a view that wraps around a ring buffer needn't be joined up.
+    pub fn to_byte_vec_with(&self, payload: &[&[u8]]) -> Vec<u8> {
+        let options = self.encode_options();
+        let len = HEADER_SIZE + options.len() + self.payload.len() +
+            payload.iter().map(|part| part.len()).sum::<usize>();
+        let mut bytes = Vec::with_capacity(len + 1);
+        bytes.extend(u16_to_u8(self.src_port));
+        bytes.extend(u16_to_u8(self.dst_port));
+        bytes.extend(u32_to_u8(len as u32));
+        bytes.extend(u32_to_u8(self.seq_num));
+        bytes.extend(u32_to_u8(self.ack_num));
+        bytes.extend(u16_to_u8(self.flags));
+        bytes.extend(&[0, 0]);
+        bytes.extend(options);
+        bytes.extend(self.payload.iter());
+        for part in payload {
+            bytes.extend_from_slice(part);
+        }
+
+        let mut sum = ones_complement_sum(&mut bytes);
+        if sum == 0 {
+            sum = !sum;
+        }
+        // Summing pads an odd length out with a zero byte
+        bytes.truncate(len);
+        bytes[18..20].copy_from_slice(&u16_to_u8(!sum));
+        bytes
+    }
+
 
     pub fn generate_checksum(&mut self) -> u16 {
         self.checksum = 0;
@@ -196,9 +529,24 @@ mod tests {
         assert!(!seg.validate());
     }
 
+    #[test]
+    fn payload_from_parts() {
+        let mut header = Segment::new(3, 4);
+        header.set_seq(1000);
+        header.set_flag(Flag::ACK);
+        header.set_window(512);
+        let mut whole = header.clone();
+        whole.set_data(vec![1, 2, 3, 4, 5]);
+
+        let bytes = header.to_byte_vec_with(&[&[1, 2], &[3, 4, 5]]);
+        assert_eq!(bytes, whole.to_byte_vec());
+        let parsed = Segment::parse(&bytes).unwrap();
+        assert_eq!(parsed.payload(), vec![1, 2, 3, 4, 5]);
+    }
+
     #[test]
     fn checksum_tpp() {
-        let mut bytes = vec![
+        let bytes = vec![
             147,
             162,
             39,
@@ -220,8 +568,8 @@ mod tests {
             197,
             37,
         ];
-        println!("TPP: {:17b}", ones_complement_sum(&mut bytes));
-        let seg = Segment::from_buf(bytes);
+        println!("TPP: {:17b}", ones_complement_sum(&mut bytes.clone()));
+        let seg = Segment::parse(&bytes).unwrap();
         println!("{:?}", seg);
         // println!("{:17b} == {:17b}", seg.checksum, seg.generate_checksum());
         assert!(seg.validate());
@@ -229,7 +577,7 @@ mod tests {
 
     #[test]
     fn checksum_handout() {
-        let mut bytes: Vec<u8> = vec![
+        let bytes: Vec<u8> = vec![
             0b00001100,
             0b00001000,
             0b00010000,
@@ -254,11 +602,148 @@ mod tests {
             0b01010101,
             0b11111111,
         ];
-        println!("TPP: {:17b}", ones_complement_sum(&mut bytes));
-        let seg = Segment::from_buf(bytes);
+        println!("TPP: {:17b}", ones_complement_sum(&mut bytes.clone()));
+        let seg = Segment::parse(&bytes).unwrap();
+        assert!(seg.validate());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut seg = Segment::new(7, 9);
+        seg.set_data(vec![1, 2, 3]);
+        let bytes = seg.to_byte_vec();
+        assert_eq!(Segment::parse(&bytes).unwrap().payload(), vec![1, 2, 3]);
+
+        assert_eq!(
+            Segment::parse(&bytes[..12]).unwrap_err(),
+            SegmentError::Truncated(12)
+        );
+        assert_eq!(
+            Segment::parse(&bytes[..22]).unwrap_err(),
+            SegmentError::SizeMismatch {
+                declared: 23,
+                actual: 22,
+            }
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[21] ^= 0xFF;
+        assert_eq!(
+            Segment::parse(&corrupted).unwrap_err(),
+            SegmentError::BadChecksum
+        );
+    }
+
+    #[test]
+    fn rst_flag() {
+        let mut seg = Segment::new(0, 0);
+        seg.set_flag(Flag::RST);
+        assert!(seg.get_flag(Flag::RST));
+        assert!(!seg.get_flag(Flag::FIN));
+        assert_eq!(seg.flags, 0x1000);
+        seg.set_options(vec![SegmentOption::MaxSegmentSize(1400)]);
+        assert!(seg.get_flag(Flag::RST));
+        seg.unset_flag(Flag::RST);
+        assert!(!seg.get_flag(Flag::RST));
         assert!(seg.validate());
     }
 
+    #[test]
+    fn options_round_trip() {
+        let mut seg = Segment::new(3, 4);
+        seg.set_flag(Flag::SYN);
+        seg.set_data(vec![9, 9]);
+        seg.set_options(vec![
+            SegmentOption::MaxSegmentSize(1400),
+            SegmentOption::WindowScale(7),
+            SegmentOption::SackPermitted,
+            SegmentOption::Timestamps { val: 10, ecr: 20 },
+        ]);
+        assert_eq!(seg.header_len(), 40);
+        assert_eq!(seg.seg_size, 42);
+
+        let parsed = Segment::parse(&seg.to_byte_vec()).unwrap();
+        assert_eq!(parsed.options(), seg.options());
+        assert_eq!(parsed.payload(), vec![9, 9]);
+        assert!(parsed.get_flag(Flag::SYN));
+        assert!(parsed.validate());
+
+        seg.set_options(vec![]);
+        assert_eq!(seg.header_len(), HEADER_SIZE);
+        assert_eq!(seg.flags & HEADER_WORDS_MASK, 0);
+        assert_eq!(seg.seg_size, 22);
+    }
+
+    #[test]
+    fn window_round_trip() {
+        let mut seg = Segment::new(3, 4);
+        assert_eq!(seg.window(), None);
+        seg.set_window(4321);
+        assert_eq!(seg.header_len(), 24);
+        let parsed = Segment::parse(&seg.to_byte_vec()).unwrap();
+        assert_eq!(parsed.window(), Some(4321));
+        assert!(parsed.options().is_empty());
+
+        seg.set_options(vec![SegmentOption::MaxSegmentSize(1400)]);
+        let parsed = Segment::parse(&seg.to_byte_vec()).unwrap();
+        assert_eq!(parsed.window(), Some(4321));
+        assert_eq!(parsed.options(), &[SegmentOption::MaxSegmentSize(1400)]);
+    }
+
+    #[test]
+    fn sack_round_trip() {
+        let mut seg = Segment::new(3, 4);
+        assert!(seg.sack_blocks().is_empty());
+        let blocks = vec![(u32::MAX - 10, 20), (100, 200)];
+        seg.set_options(vec![SegmentOption::Sack(blocks.clone())]);
+        assert_eq!(seg.header_len(), HEADER_SIZE + 20);
+        let parsed = Segment::parse(&seg.to_byte_vec()).unwrap();
+        assert_eq!(parsed.sack_blocks(), &blocks[..]);
+
+        // Blocks are 8 bytes each, anything else is malformed
+        seg.options = vec![SegmentOption::Unknown(OPT_SACK, vec![0; 6])];
+        seg.update_header_len();
+        assert_eq!(
+            Segment::parse(&seg.to_byte_vec()).unwrap_err(),
+            SegmentError::BadOption(OPT_SACK)
+        );
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let mut seg = Segment::new(3, 4);
+        assert_eq!(seg.timestamps(), None);
+        seg.set_options(vec![SegmentOption::Sack(vec![(1, 2)])]);
+        seg.set_timestamps(u32::MAX, 7);
+        seg.set_timestamps(3, u32::MAX);
+        assert_eq!(seg.header_len(), HEADER_SIZE + 20);
+        let parsed = Segment::parse(&seg.to_byte_vec()).unwrap();
+        assert_eq!(parsed.timestamps(), Some((3, u32::MAX)));
+        assert_eq!(parsed.sack_blocks(), &[(1, 2)]);
+    }
+
+    #[test]
+    fn option_parse_errors() {
+        let mut seg = Segment::new(3, 4);
+        seg.set_options(vec![SegmentOption::MaxSegmentSize(1400)]);
+
+        let mut bad_len = seg.clone();
+        bad_len.flags = (bad_len.flags & !HEADER_WORDS_MASK) | 3;
+        bad_len.checksum = bad_len.generate_checksum();
+        assert_eq!(
+            Segment::parse(&bad_len.to_byte_vec()).unwrap_err(),
+            SegmentError::BadHeaderLength(3)
+        );
+
+        let mut bad_opt = seg.clone();
+        bad_opt.options = vec![SegmentOption::Unknown(OPT_MSS, vec![1])];
+        bad_opt.checksum = bad_opt.generate_checksum();
+        assert_eq!(
+            Segment::parse(&bad_opt.to_byte_vec()).unwrap_err(),
+            SegmentError::BadOption(OPT_MSS)
+        );
+    }
+
     #[test]
     fn checksum_website() {
         let mut bytes: Vec<u8> = vec![
@@ -0,0 +1,117 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+
+/// Outgoing data from the first unacknowledged byte on, stored once in a ring buffer.  The front
+/// of it is in flight, waiting on an ACK, and the rest is waiting on the window.  Segments are
+/// views into it, cut out whenever they're sent or resent rather than kept around as copies.
+#[derive(Debug, Default)]
+pub struct SendBuffer {
+    data: VecDeque<u8>,
+    in_flight: usize,
+}
+
+impl SendBuffer {
+    pub fn new() -> SendBuffer {
+        SendBuffer::default()
+    }
+
+    /// Queues data from the application behind everything already held
+    pub fn write(&mut self, data: &[u8]) {
+        self.data.extend(data);
+    }
+
+    /// Marks the next `amt` unsent bytes as in flight
+    pub fn mark_sent(&mut self, amt: usize) {
+        assert!(amt <= self.unsent());
+        self.in_flight += amt;
+    }
+
+    /// Drops up to `amt` bytes from the front now that the peer has them, returns how many were
+    /// in flight to be dropped
+    pub fn ack(&mut self, amt: usize) -> usize {
+        let amt = min(amt, self.in_flight);
+        self.data.drain(..amt);
+        self.in_flight -= amt;
+        amt
+    }
+
+    /// The `len` bytes starting `offset` bytes past the front, in two parts since they may wrap
+    /// around the end of the ring
+    pub fn view(&self, offset: usize, len: usize) -> (&[u8], &[u8]) {
+        let (front, back) = self.data.as_slices();
+        let end = offset + len;
+        assert!(end <= self.data.len());
+        (
+            &front[min(offset, front.len())..min(end, front.len())],
+            &back[offset.saturating_sub(front.len())..end.saturating_sub(front.len())],
+        )
+    }
+
+    /// Bytes sent but not yet acknowledged
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Bytes waiting to be sent for the first time
+    pub fn unsent(&self) -> usize {
+        self.data.len() - self.in_flight
+    }
+
+    /// Bytes held, in flight or not
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.in_flight = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined((front, back): (&[u8], &[u8])) -> Vec<u8> {
+        let mut joined = front.to_vec();
+        joined.extend(back);
+        joined
+    }
+
+    #[test]
+    fn sent_then_acked() {
+        let mut buf = SendBuffer::new();
+        buf.write(&[1, 2, 3, 4, 5]);
+        assert_eq!(buf.unsent(), 5);
+        buf.mark_sent(3);
+        assert_eq!((buf.in_flight(), buf.unsent()), (3, 2));
+        assert_eq!(joined(buf.view(1, 3)), vec![2, 3, 4]);
+
+        // Only data in flight can be acknowledged
+        assert_eq!(buf.ack(10), 3);
+        assert_eq!((buf.in_flight(), buf.unsent()), (0, 2));
+        assert_eq!(joined(buf.view(0, 2)), vec![4, 5]);
+    }
+
+    #[test]
+    fn views_across_the_wrap() {
+        let mut buf = SendBuffer::new();
+        let data: Vec<u8> = (0..200).collect();
+        buf.write(&data[..100]);
+        buf.mark_sent(100);
+        buf.ack(90);
+        // Refilling after draining the front eventually wraps around the end of the ring
+        for _ in 0..10 {
+            buf.write(&data[100..]);
+            buf.mark_sent(100);
+            assert_eq!(joined(buf.view(10, 100)), &data[100..]);
+            buf.ack(100);
+        }
+        assert_eq!(buf.len(), 10);
+    }
+}
@@ -0,0 +1,535 @@
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use segment::{DroppedDatagrams, Segment};
+use tcp::{ConnectionError, TCBActions, TCBCore, TCPTuple};
+
+/// How a link treats each datagram it carries, one direction only.  The defaults make a perfect
+/// link with a 10ms delay.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LinkConfig {
+    /// Chance a datagram is lost
+    pub loss: f64,
+    /// One way delay
+    pub delay: Duration,
+    /// Up to this much more delay, picked for each datagram, so later ones may overtake it
+    pub jitter: Duration,
+    /// Chance a datagram is held back for another `delay`, letting those behind it go first
+    pub reorder: f64,
+    /// Chance a datagram arrives twice
+    pub duplicate: f64,
+    /// Chance a bit gets flipped in a datagram, which the receiver's checksum should catch
+    pub corrupt: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            loss: 0.0,
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            reorder: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+        }
+    }
+}
+
+/// What the links did to the datagrams sent over them
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub duplicated: u64,
+    pub corrupted: u64,
+}
+
+/// SplitMix64, small and good enough to make the network's misbehaviour repeatable
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, zero if `n` is
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        self.next_u64() % n
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[derive(Debug)]
+struct Host {
+    tcb: TCBCore,
+    received: Vec<u8>,
+    reading: bool,
+    unread: usize,
+    end: Option<ConnectionError>,
+}
+
+/// An in-process network of TCBs running on a virtual clock.  Time only moves when `step` jumps
+/// it to the next datagram arrival or timer, so a run that takes minutes of simulated time is
+/// over in moments, and a given seed always plays out the same way.
+///
+/// Each TCB is one end of a connection, and datagrams reach whichever TCB has the tuple they were
+/// sent on turned around.  Applications read everything as soon as it's delivered, unless their
+/// reading is paused.
+#[derive(Debug)]
+pub struct Network {
+    now: Instant,
+    rng: Rng,
+    hosts: Vec<Host>,
+    default_link: LinkConfig,
+    links: Vec<((SocketAddr, SocketAddr), LinkConfig)>,
+    in_flight: BTreeMap<(Instant, u64), (TCPTuple, Vec<u8>)>, // By arrival, then send order
+    sent: u64,
+    stats: NetworkStats,
+    dropped: DroppedDatagrams,
+    capture: Option<Vec<(Instant, TCPTuple, Segment)>>,
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Network {
+        Network {
+            now: Instant::now(),
+            rng: Rng(seed),
+            hosts: vec![],
+            default_link: LinkConfig::default(),
+            links: vec![],
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            stats: NetworkStats::default(),
+            dropped: DroppedDatagrams::default(),
+            capture: None,
+        }
+    }
+
+    /// The virtual time
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Adds a TCB, returning the id to refer to it by.  Its ISN and timestamp clock are picked
+    /// from the seed so they're the same on every run.
+    pub fn add(&mut self, mut tcb: TCBCore) -> usize {
+        let (isn, ts_offset) = (self.rng.next_u64() as u32, self.rng.next_u64() as u32);
+        tcb.set_initial_numbers(isn, ts_offset);
+        self.hosts.push(Host {
+            tcb,
+            received: vec![],
+            reading: true,
+            unread: 0,
+            end: None,
+        });
+        self.hosts.len() - 1
+    }
+
+    /// Adds both ends of a connection between two addresses, returning the client's id and then
+    /// the server's
+    pub fn add_pair(&mut self, client: SocketAddr, server: SocketAddr) -> (usize, usize) {
+        let now = self.now;
+        let client_tuple = TCPTuple {
+            src: client,
+            dst: server,
+        };
+        let server_tuple = TCPTuple {
+            src: server,
+            dst: client,
+        };
+        let client = self.add(TCBCore::new(client_tuple, now));
+        let server = self.add(TCBCore::new(server_tuple, now));
+        (client, server)
+    }
+
+    /// Link used between addresses without one of their own
+    pub fn set_default_link(&mut self, link: LinkConfig) {
+        self.default_link = link;
+    }
+
+    /// Sets the link datagrams from `from` to `to` travel over, the way back is separate
+    pub fn set_link(&mut self, from: SocketAddr, to: SocketAddr, link: LinkConfig) {
+        self.links.retain(|&(ends, _)| ends != (from, to));
+        self.links.push(((from, to), link));
+    }
+
+    fn link(&self, from: SocketAddr, to: SocketAddr) -> LinkConfig {
+        self.links
+            .iter()
+            .find(|&&(ends, _)| ends == (from, to))
+            .map_or(self.default_link, |&(_, link)| link)
+    }
+
+    pub fn tcb(&self, id: usize) -> &TCBCore {
+        &self.hosts[id].tcb
+    }
+
+    /// For configuring a TCB, best done before it's connected
+    pub fn tcb_mut(&mut self, id: usize) -> &mut TCBCore {
+        &mut self.hosts[id].tcb
+    }
+
+    /// Everything delivered to the application on `id` so far, read or not
+    pub fn received(&self, id: usize) -> &[u8] {
+        &self.hosts[id].received
+    }
+
+    /// Stalls or resumes the application on `id`.  While it's stalled delivered data sits unread
+    /// in the TCB's receive buffer, and on resuming it's all read at once.
+    pub fn set_reading(&mut self, id: usize, reading: bool) {
+        let host = &mut self.hosts[id];
+        host.reading = reading;
+        if reading && host.unread > 0 {
+            let amt = host.unread;
+            host.unread = 0;
+            let now = self.now;
+            let actions = self.hosts[id].tcb.on_app_read(amt, now);
+            self.apply(id, actions);
+        }
+    }
+
+    /// Starts keeping a copy of every datagram sent from now on, lost or not
+    pub fn capture(&mut self) {
+        self.capture.get_or_insert_with(Vec::new);
+    }
+
+    /// When, on which tuple and what was sent since `capture` was called
+    pub fn captured(&self) -> &[(Instant, TCPTuple, Segment)] {
+        self.capture.as_ref().map_or(&[], |captured| &captured[..])
+    }
+
+    /// Why the stream into `id` ended, if it has
+    pub fn end(&self, id: usize) -> Option<ConnectionError> {
+        self.hosts[id].end
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    /// Datagrams thrown away on arrival because they didn't parse
+    pub fn dropped(&self) -> DroppedDatagrams {
+        self.dropped
+    }
+
+    pub fn connect(&mut self, id: usize) {
+        let now = self.now;
+        let actions = self.hosts[id].tcb.on_app_connect(now);
+        self.apply(id, actions);
+    }
+
+    pub fn write(&mut self, id: usize, data: &[u8]) {
+        let now = self.now;
+        let actions = self.hosts[id].tcb.on_app_write(data, now);
+        self.apply(id, actions);
+    }
+
+    pub fn close(&mut self, id: usize) {
+        let now = self.now;
+        let actions = self.hosts[id].tcb.on_app_close(now);
+        self.apply(id, actions);
+    }
+
+    pub fn abort(&mut self, id: usize) {
+        let now = self.now;
+        let actions = self.hosts[id].tcb.on_app_abort(now);
+        self.apply(id, actions);
+    }
+
+    /// When the next datagram arrives or timer fires, if anything is left to happen
+    fn next_event(&self) -> Option<Instant> {
+        let arrival = self.in_flight.keys().next().map(|&(at, _)| at);
+        let timer = self.hosts.iter().filter_map(|host| host.tcb.deadline()).min();
+        match (arrival, timer) {
+            (Some(arrival), Some(timer)) => Some(min(arrival, timer)),
+            (next, None) | (None, next) => next,
+        }
+    }
+
+    /// Jumps the clock to the next arrival or timer and handles everything due by then.  Returns
+    /// false once there's nothing left to happen.
+    pub fn step(&mut self) -> bool {
+        let next = match self.next_event() {
+            Some(next) => next,
+            None => return false,
+        };
+        self.now = max(self.now, next);
+        let now = self.now;
+
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (tuple, bytes) = entry.remove();
+            self.arrive(tuple, &bytes);
+        }
+        for id in 0..self.hosts.len() {
+            if self.hosts[id].tcb.deadline().is_some_and(|deadline| deadline <= now) {
+                let actions = self.hosts[id].tcb.on_timeout(now);
+                self.apply(id, actions);
+            }
+        }
+        true
+    }
+
+    /// Steps until `done` holds, returning false if the network went quiet or `limit` of virtual
+    /// time passed first
+    pub fn run_until<F: FnMut(&Network) -> bool>(&mut self, limit: Duration, mut done: F) -> bool {
+        let give_up = self.now + limit;
+        while !done(self) {
+            if self.now > give_up || !self.step() {
+                return done(self);
+            }
+        }
+        true
+    }
+
+    /// Handles everything that happens in the next `duration`, leaving the clock at its end
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        while self.next_event().is_some_and(|next| next <= until) {
+            self.step();
+        }
+        self.now = max(self.now, until);
+    }
+
+    fn apply(&mut self, id: usize, actions: TCBActions) {
+        let tuple = self.hosts[id].tcb.tuple();
+        for bytes in actions.transmit {
+            self.send(tuple, bytes);
+        }
+        if !actions.deliver.is_empty() {
+            let amt = actions.deliver.len();
+            let host = &mut self.hosts[id];
+            host.received.extend(actions.deliver);
+            if host.reading {
+                let now = self.now;
+                let read = host.tcb.on_app_read(amt, now);
+                self.apply(id, read);
+            } else {
+                host.unread += amt;
+            }
+        }
+        if let Some(end) = actions.end {
+            let host = &mut self.hosts[id];
+            if end != ConnectionError::Closed || host.end.is_none() {
+                host.end = Some(end);
+            }
+        }
+    }
+
+    /// Puts a datagram on the link from `tuple.src` to `tuple.dst`
+    fn send(&mut self, tuple: TCPTuple, bytes: Vec<u8>) {
+        if let Some(ref mut captured) = self.capture {
+            captured.push((self.now, tuple, Segment::parse(&bytes).unwrap()));
+        }
+        let link = self.link(tuple.src, tuple.dst);
+        self.stats.sent += 1;
+        if self.rng.chance(link.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let copies = if self.rng.chance(link.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut bytes = bytes.clone();
+            if self.rng.chance(link.corrupt) {
+                let bit = self.rng.below(bytes.len() as u64 * 8) as usize;
+                bytes[bit / 8] ^= 1 << (bit % 8);
+                self.stats.corrupted += 1;
+            }
+            let jitter = self.rng.below(link.jitter.as_nanos() as u64 + 1);
+            let mut delay = link.delay + Duration::from_nanos(jitter);
+            if self.rng.chance(link.reorder) {
+                delay += link.delay;
+                self.stats.reordered += 1;
+            }
+            self.sent += 1;
+            self.in_flight.insert((self.now + delay, self.sent), (tuple, bytes));
+        }
+    }
+
+    fn arrive(&mut self, from: TCPTuple, bytes: &[u8]) {
+        let seg = match Segment::parse(bytes) {
+            Ok(seg) => seg,
+            Err(err) => {
+                self.dropped.record(&err);
+                return;
+            }
+        };
+        let to = TCPTuple {
+            src: from.dst,
+            dst: from.src,
+        };
+        if let Some(id) = self.hosts.iter().position(|host| host.tcb.tuple() == to) {
+            let now = self.now;
+            let actions = self.hosts[id].tcb.on_segment(seg, now);
+            self.apply(id, actions);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tcp::TCBState;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn bad_link() -> LinkConfig {
+        LinkConfig {
+            loss: 0.05,
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            reorder: 0.05,
+            duplicate: 0.02,
+            corrupt: 0.02,
+        }
+    }
+
+    fn payload(len: usize, seed: u64) -> Vec<u8> {
+        let mut rng = Rng(seed);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    /// Sends `data` from server to client over a bad network, returning how long it took and
+    /// what the network got up to
+    fn transfer(seed: u64, data: &[u8]) -> (Duration, NetworkStats) {
+        let mut net = Network::new(seed);
+        net.set_default_link(bad_link());
+        let start = net.now();
+        let (client, server) = net.add_pair(addr(1000), addr(2000));
+        net.connect(client);
+        assert!(net.run_until(Duration::from_secs(60), |net| {
+            net.tcb(server).state() == TCBState::Estab
+        }));
+        net.write(server, data);
+        net.close(server);
+        assert!(net.run_until(Duration::from_secs(600), |net| net.end(client).is_some()));
+        assert_eq!(net.end(client), Some(ConnectionError::Closed));
+        assert!(net.received(client) == data);
+        (net.now() - start, net.stats())
+    }
+
+    #[test]
+    fn transfers_over_a_bad_network() {
+        let data = payload(200_000, 1);
+        let (_, stats) = transfer(42, &data);
+        assert!(stats.lost > 0);
+        assert!(stats.reordered > 0);
+        assert!(stats.duplicated > 0);
+        assert!(stats.corrupted > 0);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let data = payload(50_000, 2);
+        assert_eq!(transfer(7, &data), transfer(7, &data));
+    }
+
+    #[test]
+    fn corruption_is_caught() {
+        let mut net = Network::new(3);
+        net.set_default_link(LinkConfig {
+            corrupt: 0.2,
+            ..LinkConfig::default()
+        });
+        let (client, server) = net.add_pair(addr(1000), addr(2000));
+        net.connect(client);
+        let data = payload(30_000, 3);
+        net.write(client, &data);
+        assert!(net.run_until(Duration::from_secs(600), |net| {
+            net.received(server).len() == data.len()
+        }));
+        assert!(net.received(server) == &data[..]);
+        assert_eq!(net.dropped().total(), net.stats().corrupted);
+    }
+
+    #[test]
+    fn lost_handshake_ack() {
+        // The server has data to send, so it only learns the handshake finished from the
+        // client's answer to a resent SYN-ACK
+        let mut net = Network::new(5);
+        let (client, server) = net.add_pair(addr(1000), addr(2000));
+        net.connect(client);
+        assert!(net.run_until(Duration::from_secs(1), |net| {
+            net.tcb(server).state() == TCBState::SynRecd
+        }));
+        net.write(server, &[9; 1000]);
+        net.set_link(addr(1000), addr(2000), LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        });
+        assert!(net.run_until(Duration::from_secs(1), |net| net.stats().lost == 1));
+        assert_eq!(net.tcb(client).state(), TCBState::Estab);
+        net.set_link(addr(1000), addr(2000), LinkConfig::default());
+        assert!(net.run_until(Duration::from_secs(60), |net| net.received(client).len() == 1000));
+        assert_eq!(net.tcb(server).state(), TCBState::Estab);
+        assert_eq!(net.end(server), None);
+    }
+
+    #[test]
+    fn many_seeds() {
+        let data = payload(20_000, 8);
+        for seed in 0..40 {
+            let mut net = Network::new(seed);
+            net.set_default_link(bad_link());
+            let (client, server) = net.add_pair(addr(1000), addr(2000));
+            net.connect(client);
+            // The server writes before it knows the handshake finished
+            assert!(net.run_until(Duration::from_secs(60), |net| {
+                net.tcb(server).state() == TCBState::SynRecd
+            }));
+            net.write(server, &data);
+            net.close(server);
+            assert!(
+                net.run_until(Duration::from_secs(600), |net| net.end(client).is_some()),
+                "seed {}",
+                seed
+            );
+            assert!(net.received(client) == &data[..], "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn several_connections() {
+        // Two clients of one server address, each over links of its own
+        let mut net = Network::new(4);
+        let (first, first_server) = net.add_pair(addr(1000), addr(80));
+        let (second, second_server) = net.add_pair(addr(1001), addr(80));
+        net.set_link(addr(80), addr(1000), bad_link());
+        net.set_link(addr(80), addr(1001), LinkConfig {
+            delay: Duration::from_millis(100),
+            ..LinkConfig::default()
+        });
+        net.connect(first);
+        net.connect(second);
+        net.write(first_server, &payload(20_000, 5));
+        net.write(second_server, &payload(20_000, 6));
+        assert!(net.run_until(Duration::from_secs(600), |net| {
+            net.received(first).len() == 20_000 && net.received(second).len() == 20_000
+        }));
+        assert!(net.received(first) == &payload(20_000, 5)[..]);
+        assert!(net.received(second) == &payload(20_000, 6)[..]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use isn::keyed_hash;
+use tcp::TCPTuple;
+
+/// Seconds per tick of the cookie counter
+const COUNTER_PERIOD: u64 = 64;
+/// How many ticks old a cookie may be and still be accepted
+const MAX_AGE: u32 = 1;
+const MASK_BITS: u32 = 24;
+const HASH_MASK: u32 = (1 << MASK_BITS) - 1;
+
+/// MSS values a cookie can carry, it only has room for an index into this table
+const MSS_TABLE: [u16; 8] = [536, 1024, 1220, 1400, 1440, 1460, 1480, 1500];
+
+/// SYN cookies (RFC 4987): the server's ISN encodes everything it needs to remember about a SYN,
+/// so no state has to be kept until the final ACK of the handshake proves the peer is real.
+///
+/// The top 5 bits are a counter that ticks every 64 seconds, the next 3 the peer's MSS as an
+/// index into `MSS_TABLE`, and the low 24 a keyed hash of the connection, the peer's ISN and the
+/// counter.
+pub fn encode(tuple: &TCPTuple, peer_isn: u32, mss: u16) -> u32 {
+    encode_at(tuple, peer_isn, mss, counter(SystemTime::now()))
+}
+
+/// Checks a cookie echoed back in the final ACK, returning the MSS it carries if it's genuine
+/// and fresh
+pub fn decode(tuple: &TCPTuple, peer_isn: u32, cookie: u32) -> Option<u16> {
+    decode_at(tuple, peer_isn, cookie, counter(SystemTime::now()))
+}
+
+fn counter(now: SystemTime) -> u32 {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    (secs / COUNTER_PERIOD) as u32
+}
+
+fn hash(tuple: &TCPTuple, peer_isn: u32, count: u32) -> u32 {
+    keyed_hash((tuple, peer_isn, count)) as u32 & HASH_MASK
+}
+
+fn encode_at(tuple: &TCPTuple, peer_isn: u32, mss: u16, count: u32) -> u32 {
+    // Round down, the peer can always take smaller segments than it asked for
+    let mss_index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+    ((count & 0x1F) << 27) | (mss_index << MASK_BITS) | hash(tuple, peer_isn, count)
+}
+
+fn decode_at(tuple: &TCPTuple, peer_isn: u32, cookie: u32, now: u32) -> Option<u16> {
+    (0..=MAX_AGE)
+        .map(|age| now.wrapping_sub(age))
+        .find(|&count| {
+            cookie >> 27 == count & 0x1F && cookie & HASH_MASK == hash(tuple, peer_isn, count)
+        })
+        .map(|_| MSS_TABLE[(cookie >> MASK_BITS & 0x7) as usize])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuple() -> TCPTuple {
+        TCPTuple {
+            src: "127.0.0.1:1000".parse().unwrap(),
+            dst: "127.0.0.1:2000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let cookie = encode_at(&tuple(), 77, 1460, 500);
+        assert_eq!(decode_at(&tuple(), 77, cookie, 500), Some(1460));
+        assert_eq!(decode_at(&tuple(), 77, cookie, 501), Some(1460));
+
+        // MSS values between table entries round down
+        let cookie = encode_at(&tuple(), 77, 1300, 500);
+        assert_eq!(decode_at(&tuple(), 77, cookie, 500), Some(1220));
+        let cookie = encode_at(&tuple(), 77, 100, 500);
+        assert_eq!(decode_at(&tuple(), 77, cookie, 500), Some(536));
+    }
+
+    #[test]
+    fn rejects_forgeries() {
+        let cookie = encode_at(&tuple(), 77, 1500, 500);
+        assert_eq!(decode_at(&tuple(), 78, cookie, 500), None);
+        assert_eq!(decode_at(&tuple(), 77, cookie ^ 1, 500), None);
+        let other = TCPTuple {
+            src: tuple().src,
+            dst: "127.0.0.1:2001".parse().unwrap(),
+        };
+        assert_eq!(decode_at(&other, 77, cookie, 500), None);
+    }
+
+    #[test]
+    fn expires() {
+        let cookie = encode_at(&tuple(), 77, 1500, 500);
+        assert_eq!(decode_at(&tuple(), 77, cookie, 502), None);
+        assert_eq!(decode_at(&tuple(), 77, cookie, 499), None);
+    }
+}
@@ -1,14 +1,37 @@
 use segment::*;
 use std::net::*;
 use std::sync::mpsc::*;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::collections::VecDeque;
 use std::cmp::*;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::error::Error;
+use std::io;
+use rto::RttEstimator;
+use isn;
+use syncookie;
+use reassembly::Reassembly;
+use sendbuf::SendBuffer;
+use congestion::{seq_geq, CongestionControl, NewReno};
 use utils::*;
 
-const WINDOW_SIZE: usize = 65000;
+pub const WINDOW_SIZE: usize = 65000; // Default for both the send and receive windows
+const MAX_WINDOW_SHIFT: u8 = 14; // RFC 7323
+pub const MAX_WINDOW: usize = (u16::MAX as usize) << MAX_WINDOW_SHIFT;
 const MAX_PAYLOAD_SIZE: usize = 1500;
-const TIMEOUT: u64 = 1; // In seconds
+const TIMEOUT: u64 = 1; // In seconds, longest the event loop sleeps without a timer due
+const TIME_WAIT: u64 = 2; // In seconds, twice the longest we expect a segment to linger
+const ACK_DELAY: u64 = 200; // In milliseconds, how long an ACK may wait for a second segment
+const SYN_RETRIES: u32 = 6;
+const DATA_RETRIES: u32 = 12;
+const MAX_SACK_BLOCKS: usize = 4;
+const MAX_PERSIST: u64 = 60; // In seconds, longest between zero window probes
+const TIMER_SLACK: u64 = 10; // In milliseconds, retransmission deadlines this close fire together
+const PAWS_IDLE: u64 = 24 * 24 * 60 * 60; // In seconds, after this long TS.Recent is too old to trust
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TCBState {
@@ -16,9 +39,38 @@ pub enum TCBState {
     SynSent,
     SynRecd,
     Estab,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
     Closed,
 }
 
+impl TCBState {
+    /// Whether the peer may still send us data
+    fn can_recv(&self) -> bool {
+        matches!(
+            *self,
+            TCBState::Estab | TCBState::FinWait1 | TCBState::FinWait2
+        )
+    }
+
+    /// Whether we may still send the peer data
+    fn can_send(&self) -> bool {
+        matches!(*self, TCBState::Estab | TCBState::CloseWait)
+    }
+
+    /// Whether the handshake is over, the RFC 793 synchronized states
+    fn is_synchronized(&self) -> bool {
+        !matches!(
+            *self,
+            TCBState::Listen | TCBState::SynSent | TCBState::SynRecd | TCBState::Closed
+        )
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TCPTuple {
     pub src: SocketAddr,
@@ -26,459 +78,2704 @@ pub struct TCPTuple {
 }
 
 
+/// A window as it goes in a segment's 16 bit field once scaled down by `shift`
+fn window_field(window: usize, shift: u8) -> u16 {
+    min(window >> shift, u16::MAX as usize) as u16
+}
+
+/// Smallest window scale that lets all of a `size` byte receive buffer be advertised
+fn window_shift(size: usize) -> u8 {
+    let mut shift = 0;
+    while shift < MAX_WINDOW_SHIFT && size >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+}
+
+/// Options one side offers in its SYN.  A peer whose SYN carried no options at all is treated as a
+/// legacy TPP peer and is never sent any.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SynOptions {
+    pub mss: u16,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub timestamps: bool,
+}
+
+impl Default for SynOptions {
+    fn default() -> SynOptions {
+        SynOptions {
+            mss: MAX_PAYLOAD_SIZE as u16,
+            window_scale: Some(0),
+            sack_permitted: true,
+            timestamps: true,
+        }
+    }
+}
+
+impl SynOptions {
+    fn from_segment(seg: &Segment) -> Option<SynOptions> {
+        if seg.options().is_empty() {
+            return None;
+        }
+        let mut opts = SynOptions {
+            mss: MAX_PAYLOAD_SIZE as u16,
+            window_scale: None,
+            sack_permitted: false,
+            timestamps: false,
+        };
+        for opt in seg.options() {
+            match *opt {
+                SegmentOption::MaxSegmentSize(mss) => opts.mss = mss,
+                SegmentOption::WindowScale(shift) => opts.window_scale = Some(shift),
+                SegmentOption::SackPermitted => opts.sack_permitted = true,
+                SegmentOption::Timestamps { .. } => opts.timestamps = true,
+                _ => {}
+            }
+        }
+        Some(opts)
+    }
+
+    /// What to put in a SYN-ACK: only options the peer's SYN offered may be echoed back
+    fn answer(&self, peer: &SynOptions) -> SynOptions {
+        SynOptions {
+            mss: self.mss,
+            window_scale: self.window_scale.and(peer.window_scale),
+            sack_permitted: self.sack_permitted && peer.sack_permitted,
+            timestamps: self.timestamps && peer.timestamps,
+        }
+    }
+
+    fn to_options(self) -> Vec<SegmentOption> {
+        let mut opts = vec![SegmentOption::MaxSegmentSize(self.mss)];
+        if let Some(shift) = self.window_scale {
+            opts.push(SegmentOption::WindowScale(shift));
+        }
+        if self.sack_permitted {
+            opts.push(SegmentOption::SackPermitted);
+        }
+        if self.timestamps {
+            opts.push(SegmentOption::Timestamps { val: 0, ecr: 0 });
+        }
+        opts
+    }
+}
+
 #[derive(Debug)]
 pub enum TCBInput {
     SendSyn,
     Receive(Segment),
     Send(Vec<u8>),
     Close,
+    /// Tear the connection down immediately, telling the peer with a RST
+    Abort,
+    /// The application has read data, which may open the window enough to tell the peer
+    WindowUpdate,
+}
+
+/// Why the byte stream from a TCB ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The connection was closed and everything the peer sent has been read
+    Closed,
+    /// The peer reset the connection
+    Reset,
+    /// We aborted the connection ourselves
+    Aborted,
+    /// The peer stopped acknowledging anything we sent
+    TimedOut,
+    /// The connection sat idle and the peer never answered our keepalive probes
+    Unreachable,
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            ConnectionError::Closed => write!(f, "connection closed"),
+            ConnectionError::Reset => write!(f, "connection reset"),
+            ConnectionError::Aborted => write!(f, "connection aborted"),
+            ConnectionError::TimedOut => write!(f, "connection timed out"),
+            ConnectionError::Unreachable => write!(f, "peer unreachable"),
+        }
+    }
+}
+
+impl Error for ConnectionError {}
+
+impl From<ConnectionError> for io::Error {
+    fn from(err: ConnectionError) -> io::Error {
+        let kind = match err {
+            ConnectionError::Closed => io::ErrorKind::UnexpectedEof,
+            ConnectionError::Reset => io::ErrorKind::ConnectionReset,
+            ConnectionError::Aborted => io::ErrorKind::ConnectionAborted,
+            ConnectionError::TimedOut => io::ErrorKind::TimedOut,
+            ConnectionError::Unreachable => io::ErrorKind::HostUnreachable,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// When to check up on a connection nothing has been heard from, per RFC 1122
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Keepalive {
+    /// Silence before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes before the peer is given up on
+    pub probes: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Keepalive {
+        Keepalive {
+            idle: Duration::from_secs(2 * 60 * 60),
+            interval: Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
+
+/// Data delivered in order that the application hasn't read yet, and why the stream ended once
+/// it has
+#[derive(Debug, Default)]
+struct Unread {
+    bytes: VecDeque<u8>,
+    end: Option<ConnectionError>,
+}
+
+/// Receive buffer shared between the TCB and the application's `TCBOutput`.  It never holds more
+/// than the receive window, since the TCB only accepts data that fits in the window it advertised.
+#[derive(Debug, Default)]
+struct RecvBuffer {
+    unread: Mutex<Unread>,
+    readable: Condvar,
+    consumed: AtomicUsize, // Read since the TCB last heard
+    update_pending: AtomicBool,
+}
+
+impl RecvBuffer {
+    fn push(&self, data: &[u8]) {
+        self.unread.lock().unwrap().bytes.extend(data);
+        self.readable.notify_all();
+    }
+
+    /// Nothing more is coming, readers get `end` once they've read everything before it.  An
+    /// error replaces a clean end, but not the other way round.
+    fn finish(&self, end: ConnectionError) {
+        let mut unread = self.unread.lock().unwrap();
+        if end != ConnectionError::Closed || unread.end.is_none() {
+            unread.end = Some(end);
+        }
+        self.readable.notify_all();
+    }
 }
 
+/// The application's end of a TCB's byte stream.  Reading from it frees receive buffer space,
+/// which gets advertised back to the peer once it's worth a segment.
 #[derive(Debug)]
-pub struct TCB {
+pub struct TCBOutput {
+    buffer: Arc<RecvBuffer>,
+    input: Sender<TCBInput>,
+}
+
+impl TCBOutput {
+    /// Fills `buf` with as much as has arrived, waiting only if nothing has.  Returns how many
+    /// bytes were read, or why the stream ended once everything before the end has been read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, ConnectionError> {
+        let mut unread = self.buffer.unread.lock().unwrap();
+        while unread.bytes.is_empty() {
+            if let Some(end) = unread.end {
+                return Err(end);
+            }
+            unread = self.buffer.readable.wait(unread).unwrap();
+        }
+        let amt = min(buf.len(), unread.bytes.len());
+        let (front, back) = unread.bytes.as_slices();
+        let from_front = min(amt, front.len());
+        buf[..from_front].copy_from_slice(&front[..from_front]);
+        buf[from_front..amt].copy_from_slice(&back[..amt - from_front]);
+        unread.bytes.drain(..amt);
+        drop(unread);
+
+        // Reads are tallied up so the TCB hears about them with one message at a time at most
+        self.buffer.consumed.fetch_add(amt, Ordering::SeqCst);
+        if amt > 0 && !self.buffer.update_pending.swap(true, Ordering::SeqCst) {
+            // The TCB may already be gone, in which case there's nobody to update
+            let _ = self.input.send(TCBInput::WindowUpdate);
+        }
+        Ok(amt)
+    }
+
+    /// Reads a single byte
+    pub fn recv(&self) -> Result<u8, ConnectionError> {
+        let mut byte = [0];
+        self.read(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+/// What handling an event left for whoever drives a `TCBCore` to do
+#[derive(Debug, Default)]
+pub struct TCBActions {
+    /// Encoded segments to send to the peer, in order
+    pub transmit: Vec<Vec<u8>>,
+    /// Data that arrived in order, for the application
+    pub deliver: Vec<u8>,
+    /// Set once nothing more will be delivered, to why the stream ended
+    pub end: Option<ConnectionError>,
+    /// When `on_timeout` is next due, if ever
+    pub deadline: Option<Instant>,
+}
+
+/// A segment waiting to be acknowledged, along with its retransmission timer and what the peer's
+/// SACK blocks say about it
+#[derive(Debug)]
+struct SentSeg {
+    seq: u32,
+    len: u32, // Sequence space it takes up
+    // SYNs and FINs are kept whole for their options, data is cut from the send buffer again
+    control: Option<Segment>,
+    sent_at: Instant,  // Last (re)transmission
+    deadline: Instant, // Retransmitted if still unacknowledged by then
+    // Karn's algorithm, an ACK for it can't be told apart from one for an earlier transmission
+    retransmitted: bool,
+    sacked: bool,
+    // Already retransmitted to fill a SACK hole, so later holes don't resend it again
+    resent: bool,
+}
+
+/// The protocol state machine of a connection, free of any I/O.  Everything that happens to it
+/// comes in through an `on_` method along with the current time, and each of those returns the
+/// segments to send, the data to deliver and when it next needs `on_timeout`.
+#[derive(Debug)]
+pub struct TCBCore {
     tuple: TCPTuple,
     state: TCBState,
-    socket: UdpSocket,
-    data_input: Receiver<TCBInput>,
-    byte_output: Sender<u8>,
-
-    send_buffer: VecDeque<u8>, // Data to be sent that hasn't been
-    send_window: VecDeque<u8>,
-    recv_window: VecDeque<Option<u8>>,
+    now: Instant, // Of the event being handled
+    actions: TCBActions, // Piling up for the event being handled
+    recv_capacity: usize,
+    unread: usize, // Delivered, but not yet read by the application
+    advertised: usize, // Receive window in our latest segment
+
+    send_buffer: SendBuffer, // Everything from seq_base on, sent or not
+    send_window_size: usize, // Most we'll have in flight, whatever the peer advertises
+    nodelay: bool,
+    reassembly: Reassembly, // Data waiting on a hole before it
+    delayed_ack_bytes: usize, // Received in order since our last ACK
+    ack_delay: Duration,
+    ack_deadline: Option<Instant>,
 
     seq_base: u32,
     ack_base: u32,
+    peer_window: usize,
+    snd_wl1: u32, // Sequence and ACK numbers of the segment peer_window came from
+    snd_wl2: u32,
+
+    local_opts: SynOptions,
+    peer_opts: Option<SynOptions>,
 
-    unacked_segs: VecDeque<Segment>,
+    close_requested: bool,
+    fin_seq: Option<u32>,
+    time_wait: Duration,
+    time_wait_until: Option<Instant>,
+
+    unacked_segs: VecDeque<SentSeg>,
     dupe_acks: u32,
+    cc: Box<dyn CongestionControl>,
+    rtt: RttEstimator,
+    // Timestamps (RFC 7323), our clock counts milliseconds from ts_epoch starting at ts_offset.
+    // ts_recent is the peer's latest timestamp, which we echo, and when it arrived.
+    ts_epoch: Instant,
+    ts_offset: u32,
+    ts_recent: Option<(u32, Instant)>,
+    // Consecutive timeouts without any new data being acknowledged
+    retries: u32,
+    syn_retries: u32,
+    data_retries: u32,
+
+    keepalive: Option<Keepalive>,
+    keepalive_deadline: Option<Instant>,
+    keepalive_probes: u32, // Sent since the peer was last heard from
+
+    // Zero window probing, the backoff only resets once the window opens
+    persist_deadline: Option<Instant>,
+    persist_backoff: u32,
+    persist_unanswered: u32,
 }
 
-impl TCB {
-    pub fn new(tuple: TCPTuple, udp_sock: UdpSocket) -> (TCB, Sender<TCBInput>, Receiver<u8>) {
-        let (data_input_tx, data_input_rx) = channel();
-        let (byte_output_tx, byte_output_rx) = channel();
-        (
-            TCB {
-                tuple: tuple,
-                state: TCBState::Listen,
-                socket: udp_sock,
-                data_input: data_input_rx,
-                byte_output: byte_output_tx,
-
-                send_buffer: VecDeque::new(),
-                send_window: VecDeque::new(),
-                recv_window: VecDeque::from(vec![Option::None; WINDOW_SIZE]),
-
-                seq_base: 1,
-                ack_base: 1,
-
-                unacked_segs: VecDeque::new(),
-                dupe_acks: 0,
-            },
-            data_input_tx,
-            byte_output_rx,
-        )
+impl TCBCore {
+    pub fn new(tuple: TCPTuple, now: Instant) -> TCBCore {
+        TCBCore {
+            tuple,
+            state: TCBState::Listen,
+            now,
+            actions: TCBActions::default(),
+            recv_capacity: WINDOW_SIZE,
+            unread: 0,
+            advertised: WINDOW_SIZE,
+
+            send_buffer: SendBuffer::new(),
+            send_window_size: WINDOW_SIZE,
+            nodelay: false,
+            reassembly: Reassembly::new(),
+            delayed_ack_bytes: 0,
+            ack_delay: Duration::from_millis(ACK_DELAY),
+            ack_deadline: None,
+
+            seq_base: isn::generate(&tuple),
+            ack_base: 0,
+            peer_window: WINDOW_SIZE,
+            snd_wl1: 0,
+            snd_wl2: 0,
+
+            local_opts: SynOptions::default(),
+            peer_opts: None,
+
+            close_requested: false,
+            fin_seq: None,
+            time_wait: Duration::from_secs(TIME_WAIT),
+            time_wait_until: None,
+
+            unacked_segs: VecDeque::new(),
+            dupe_acks: 0,
+            cc: Box::new(NewReno::new(MAX_PAYLOAD_SIZE)),
+            rtt: RttEstimator::default(),
+            ts_epoch: now,
+            // The ISN takes the low half of the hash, so a timestamp gives nothing away about it
+            ts_offset: (isn::keyed_hash(tuple) >> 32) as u32,
+            ts_recent: None,
+            retries: 0,
+            syn_retries: SYN_RETRIES,
+            data_retries: DATA_RETRIES,
+
+            keepalive: None,
+            keepalive_deadline: None,
+            keepalive_probes: 0,
+
+            persist_deadline: None,
+            persist_backoff: 0,
+            persist_unanswered: 0,
+        }
     }
 
-    pub fn recv(out: &Receiver<u8>, amt: u32) -> Result<Vec<u8>, RecvError> {
-        let mut buf = Vec::with_capacity(amt as usize);
-        while buf.len() < amt as usize {
-            buf.push(out.recv()?);
-        }
-        Ok(buf)
+    pub fn state(&self) -> TCBState {
+        self.state
     }
 
-    pub fn run_tcp(&mut self) {
-        'event_loop: while self.state != TCBState::Closed {
-            self.handle_input_recv();
-        }
+    pub fn tuple(&self) -> TCPTuple {
+        self.tuple
     }
 
-    fn send_syn(&mut self) {
-        let mut syn = self.make_seg();
-        syn.set_flag(Flag::SYN);
-        syn.set_seq(self.seq_base);
-        self.send_seg(syn);
-        self.state = TCBState::SynSent;
+    /// The application opens the connection, sending our SYN
+    pub fn on_app_connect(&mut self, now: Instant) -> TCBActions {
+        self.event(now, |tcb| tcb.send_syn())
     }
 
-    fn handle_input_recv(&mut self) {
-        match self.data_input.recv_timeout(Duration::from_secs(TIMEOUT)) {
-            Ok(input) => {
-                match input {
-                    TCBInput::SendSyn => self.send_syn(),
-                    TCBInput::Receive(seg) => self.handle_seg(seg),
-                    TCBInput::Send(data) => {
-                        self.send_buffer.extend(data);
-                        self.fill_send_window();
-                    }
-                    TCBInput::Close => {
-                        self.send_close();
-                    }
-                }
-            }
-            Err(e) if e == RecvTimeoutError::Timeout => {
-                self.handle_resend();
+    pub fn on_segment(&mut self, seg: Segment, now: Instant) -> TCBActions {
+        self.event(now, |tcb| tcb.handle_seg(seg))
+    }
+
+    /// The application has more data to send
+    pub fn on_app_write(&mut self, data: &[u8], now: Instant) -> TCBActions {
+        self.event(now, |tcb| {
+            // Nothing more can be sent once the application has asked to close
+            if !tcb.close_requested {
+                tcb.send_buffer.write(data);
+                tcb.fill_send_window();
             }
-            Err(e) => panic!(e),
-        }
+        })
     }
 
-    fn fill_send_window(&mut self) {
-        if self.state != TCBState::Estab {
-            return;
-        }
-        let orig_window_len = self.send_window.len();
-        let send_amt = min(self.send_buffer.len(), WINDOW_SIZE - orig_window_len);
-        self.send_window.extend(
-            self.send_buffer.iter().take(send_amt),
-        );
-        let data_to_send = self.send_buffer.drain(..send_amt).collect::<Vec<u8>>();
-        let next_seq = self.seq_base.wrapping_add(orig_window_len as u32);
-        self.send_data(data_to_send, next_seq);
+    /// The application has read `amt` of the bytes delivered to it, freeing up receive buffer
+    pub fn on_app_read(&mut self, amt: usize, now: Instant) -> TCBActions {
+        self.event(now, |tcb| tcb.handle_read(amt))
     }
 
-    fn send_data(&mut self, mut data: Vec<u8>, next_seq: u32) {
-        let mut sent = 0;
-        let bytes_to_send = data.len();
-        while sent < bytes_to_send {
-            let size = min(MAX_PAYLOAD_SIZE, data.len());
-            let payload: Vec<u8> = data.drain(..size).collect();
-            let mut seg = self.make_seg();
-            seg.set_seq(next_seq.wrapping_add(sent as u32));
-            seg.set_data(payload);
-            self.send_seg(seg);
-            sent += size;
-        }
+    /// The application is done sending, our FIN follows the data already written
+    pub fn on_app_close(&mut self, now: Instant) -> TCBActions {
+        self.event(now, |tcb| tcb.send_close())
     }
 
-    fn handle_seg(&mut self, seg: Segment) {
-        // println!("Got seg: {:?}", seg);
-        self.handle_acks(&seg); // sender
-        self.handle_shake(&seg);
-        self.handle_payload(&seg); // receiver
-        if seg.get_flag(Flag::FIN) {
-            self.handle_close();
-        }
+    /// Tears the connection down immediately, telling the peer with a RST
+    pub fn on_app_abort(&mut self, now: Instant) -> TCBActions {
+        self.event(now, |tcb| tcb.abort())
     }
 
-    fn handle_payload(&mut self, seg: &Segment) {
-        if self.state != TCBState::Estab {
-            return;
-        }
-        let seq_lb = self.ack_base;
-        let seq_ub = seq_lb.wrapping_add(WINDOW_SIZE as u32);
-        if in_wrapped_range((seq_lb, seq_ub), seg.seq_num()) {
-            let window_index_base = seg.seq_num().wrapping_sub(self.ack_base) as usize;
-            for (i, byte) in seg.payload().iter().enumerate() {
-                self.recv_window[window_index_base + i] = Some(*byte);
-            }
-        } else if seg.payload().len() > 0 {
-            // println!(
-            //     "\x1b[31m Seq {} out of range (expected {}), ignoring\x1b[0m",
-            //     seg.seq_num(),
-            //     self.ack_base
-            // );
-        }
-
-        if seg.seq_num() == self.ack_base {
-            loop {
-                match self.recv_window.front() {
-                    Some(opt) => {
-                        match *opt {
-                            Some(byte) => self.byte_output.send(byte).unwrap(),
-                            None => break,
-                        }
-                    }
-                    _ => break,
+    /// Fires every timer that's due by `now`, harmless to call early
+    pub fn on_timeout(&mut self, now: Instant) -> TCBActions {
+        self.event(now, |tcb| {
+            if tcb.rto_deadline().is_some_and(|deadline| now >= deadline) {
+                tcb.handle_rto(now);
+            }
+            if tcb.ack_deadline.is_some_and(|deadline| now >= deadline) {
+                tcb.send_ack_now(None);
+            }
+            if tcb.keepalive_deadline.is_some_and(|deadline| now >= deadline) {
+                tcb.handle_keepalive(now);
+            }
+            if tcb.persist_deadline.is_some_and(|deadline| now >= deadline) {
+                tcb.handle_persist(now);
+            }
+            if let Some(deadline) = tcb.time_wait_until {
+                if tcb.state == TCBState::TimeWait && now >= deadline {
+                    tcb.close(None);
                 }
-                self.ack_base = self.ack_base.wrapping_add(1);
-                self.recv_window.pop_front();
-                self.recv_window.push_back(None);
             }
-            let mut ack = self.make_seg();
-            ack.set_flag(Flag::ACK);
-            ack.set_ack_num(self.ack_base);
-            // TODO: Delayed ack
-            self.send_ack(ack);
-        } else if !seg.get_flag(Flag::ACK) {
-            // println!(
-            //     "\x1b[32m Out of order Seq {} (expected {}), ignoring\x1b[0m",
-            //     seg.seq_num(),
-            //     self.ack_base
-            // );
+        })
+    }
+
+    /// When the earliest timer is due, never once the connection is closed
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.state == TCBState::Closed {
+            return None;
         }
+        let deadlines = [
+            self.rto_deadline(),
+            self.time_wait_until,
+            self.ack_deadline,
+            self.keepalive_deadline,
+            self.persist_deadline,
+        ];
+        deadlines.iter().flatten().min().cloned()
     }
 
-    fn handle_acks(&mut self, seg: &Segment) {
-        let ack_lb = self.seq_base.wrapping_add(1);
-        let ack_ub = ack_lb.wrapping_add(WINDOW_SIZE as u32);
-        if seg.get_flag(Flag::ACK) && in_wrapped_range((ack_lb, ack_ub), seg.ack_num()) {
-            self.unacked_segs.retain(|unacked_seg: &Segment| {
-                in_wrapped_range(
-                    (
-                        seg.ack_num(),
-                        seg.ack_num().wrapping_add(WINDOW_SIZE as u32),
-                    ),
-                    unacked_seg.seq_num(),
-                )
-            });
+    /// Handles one event, time never runs backwards even if the caller's clock does
+    fn event<F: FnOnce(&mut TCBCore)>(&mut self, now: Instant, handle: F) -> TCBActions {
+        self.now = max(self.now, now);
+        handle(self);
+        self.update_persist(self.now);
+        self.take_actions()
+    }
 
-            let num_acked_bytes = seg.ack_num().wrapping_sub(self.seq_base) as usize;
-            self.seq_base = seg.ack_num();
+    fn take_actions(&mut self) -> TCBActions {
+        let mut actions = mem::take(&mut self.actions);
+        actions.deadline = self.deadline();
+        actions
+    }
 
-            // Handle payload data, only valid after Estab
-            if self.state == TCBState::Estab {
-                self.send_window.drain(..num_acked_bytes);
-                self.fill_send_window();
-            }
+    /// Picks the ISN and the offset of our timestamp clock instead of deriving them from the
+    /// tuple, so a run can be repeated exactly.  Must be called before the handshake.
+    pub fn set_initial_numbers(&mut self, isn: u32, ts_offset: u32) {
+        self.seq_base = isn;
+        self.ts_offset = ts_offset;
+    }
+
+    /// Sets the options offered in our SYN or SYN-ACK, must be called before the handshake
+    pub fn set_syn_options(&mut self, opts: SynOptions) {
+        self.local_opts = opts;
+    }
 
+    /// The options both sides agreed on, `None` until the handshake completes or for legacy peers
+    pub fn negotiated_options(&self) -> Option<SynOptions> {
+        if self.state == TCBState::Listen || self.state == TCBState::SynSent {
+            return None;
         }
+        self.peer_opts.map(|peer| self.local_opts.answer(&peer))
+    }
 
-        let dupe_ack_lb = self.seq_base.wrapping_sub((WINDOW_SIZE - 1) as u32);
-        let dupe_ack_ub = dupe_ack_lb.wrapping_add(WINDOW_SIZE as u32);
-        if self.state == TCBState::Estab && seg.get_flag(Flag::ACK) &&
-            in_wrapped_range((dupe_ack_lb, dupe_ack_ub), seg.seq_num())
-        {
-            self.dupe_acks += 1;
-            if self.dupe_acks >= 3 {
-                self.handle_resend();
-                self.dupe_acks = 0;
-                // println!("\x1b[31m Triple Duplicate ACK! Resending \x1b[0m");
-            }
+    /// Sets how much data may be in flight and how big the receive buffer is, each up to
+    /// `MAX_WINDOW`.  The window scale offered in our SYN grows to fit the receive buffer, a peer
+    /// that doesn't scale windows only ever sees 64 KB of it.  Must be called before the handshake.
+    pub fn set_window_sizes(&mut self, send: usize, recv: usize) {
+        self.send_window_size = min(send, MAX_WINDOW);
+        let recv = min(recv, MAX_WINDOW);
+        self.recv_capacity = recv;
+        self.advertised = recv;
+        if let Some(shift) = self.local_opts.window_scale {
+            self.local_opts.window_scale = Some(max(shift, window_shift(recv)));
         }
     }
 
-    fn handle_shake(&mut self, seg: &Segment) {
-        match self.state {
-            TCBState::Listen => {
-                if seg.get_flag(Flag::SYN) {
-                    self.state = TCBState::SynRecd;
-                    self.ack_base = seg.seq_num().wrapping_add(1);
-                    let mut synack = self.make_seg();
-                    synack.set_flag(Flag::SYN);
-                    synack.set_flag(Flag::ACK);
-                    synack.set_seq(self.seq_base);
-                    synack.set_ack_num(self.ack_base);
-                    self.send_seg(synack);
-                }
-            }
-            TCBState::SynSent => {
-                if seg.get_flag(Flag::SYN) && seg.get_flag(Flag::ACK) {
-                    self.state = TCBState::Estab;
-                    self.ack_base = seg.seq_num().wrapping_add(1);
-                    let mut ack = self.make_seg();
-                    ack.set_flag(Flag::ACK);
-                    ack.set_ack_num(self.ack_base);
-                    self.send_ack(ack);
-                    self.fill_send_window();
-                }
-            }
-            TCBState::SynRecd => {
-                // TODO: Verify what seq num should be here
-                if seg.get_flag(Flag::ACK) {
-                    self.state = TCBState::Estab;
-                    self.fill_send_window();
-                }
-            }
-            TCBState::Estab => {}
-            TCBState::Closed => {}
+    /// Shift applied to the windows we advertise, zero unless both sides agreed to scaling
+    fn recv_shift(&self) -> u8 {
+        self.negotiated_options()
+            .and_then(|opts| opts.window_scale)
+            .unwrap_or(0)
+    }
+
+    /// Shift applied to the windows the peer advertises
+    fn send_shift(&self) -> u8 {
+        match self.negotiated_options().and_then(|opts| opts.window_scale) {
+            Some(_) => self.peer_opts.and_then(|peer| peer.window_scale).unwrap_or(0),
+            None => 0,
         }
     }
 
+    /// Our receive window as it goes in a segment, which is never scaled in a SYN
+    fn advertised_window(&self, syn: bool) -> u16 {
+        let shift = if syn { 0 } else { self.recv_shift() };
+        window_field(self.recv_window(), shift)
+    }
 
-    fn handle_close(&mut self) {
-        self.state = TCBState::Closed;
+    /// Room left in the receive buffer
+    fn recv_window(&self) -> usize {
+        self.recv_capacity.saturating_sub(self.unread)
     }
 
-    fn send_close(&mut self) {
-        let mut fin = self.make_seg();
-        fin.set_flag(Flag::FIN);
-        fin.set_seq(self.seq_base);
-        self.send_seg(fin);
-        self.state = TCBState::Closed;
+    fn ts_enabled(&self) -> bool {
+        self.negotiated_options().is_some_and(|opts| opts.timestamps)
     }
 
-    fn handle_resend(&mut self) {
-        match self.unacked_segs.front() {
-            Some(ref seg) => self.resend_seg(&seg),
-            _ => {}
+    /// Our timestamp clock, in milliseconds
+    fn ts_now(&self) -> u32 {
+        let elapsed = self.now.duration_since(self.ts_epoch);
+        (elapsed.as_millis() as u32).wrapping_add(self.ts_offset)
+    }
+
+    /// Our clock and the peer's latest timestamp, if timestamps were agreed on
+    fn timestamps(&self) -> Option<(u32, u32)> {
+        if !self.ts_enabled() {
+            return None;
         }
+        Some((self.ts_now(), self.ts_recent.map_or(0, |(val, _)| val)))
     }
 
-    fn make_seg(&self) -> Segment {
-        Segment::new(self.tuple.src.port(), self.tuple.dst.port())
+    fn stamp(&self, seg: &mut Segment) {
+        if let Some((val, ecr)) = self.timestamps() {
+            seg.set_timestamps(val, ecr);
+        }
     }
 
-    fn send_seg(&mut self, seg: Segment) {
-        self.resend_seg(&seg);
-        self.unacked_segs.push_back(seg);
+    fn sack_enabled(&self) -> bool {
+        self.negotiated_options().is_some_and(|opts| opts.sack_permitted)
     }
 
-    fn send_ack(&self, seg: Segment) {
-        self.resend_seg(&seg);
+    /// Largest payload we may put in one segment, limited by the MSS the peer advertised
+    fn send_mss(&self) -> usize {
+        match self.peer_opts {
+            Some(peer) => min(MAX_PAYLOAD_SIZE, peer.mss as usize),
+            None => MAX_PAYLOAD_SIZE,
+        }
     }
 
-    fn resend_seg(&self, seg: &Segment) {
-        let bytes = seg.to_byte_vec();
-        self.socket.send_to(&bytes[..], &self.tuple.dst).unwrap();
+    /// How long to linger in `TimeWait` acknowledging retransmitted FINs before closing
+    pub fn set_time_wait(&mut self, time_wait: Duration) {
+        self.time_wait = time_wait;
     }
-}
 
+    /// Turns off Nagle's algorithm, so small writes go out right away even while earlier data is
+    /// unacknowledged
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
-    use std::thread;
+    /// How long an ACK for in-order data may be held back waiting for another segment, zero
+    /// acknowledges every segment immediately
+    pub fn set_ack_delay(&mut self, ack_delay: Duration) {
+        self.ack_delay = ack_delay;
+    }
 
-    type TcbTup = (TCB, Sender<TCBInput>, Receiver<u8>);
-    pub fn tcb_pair() -> (TcbTup, TcbTup, UdpSocket, UdpSocket) {
-        let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
-        let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
-        let server_tuple = TCPTuple {
-            src: server_sock.local_addr().unwrap(),
-            dst: client_sock.local_addr().unwrap(),
-        };
-        let client_tuple = TCPTuple {
-            src: client_sock.local_addr().unwrap(),
-            dst: server_sock.local_addr().unwrap(),
-        };
-        let server_tuple = TCB::new(server_tuple, server_sock.try_clone().unwrap());
-        let client_tuple = TCB::new(client_tuple, client_sock.try_clone().unwrap());
-        (server_tuple, client_tuple, server_sock, client_sock)
+    /// Probes the peer once the connection has been idle for a while, closing it as unreachable
+    /// if it doesn't answer.  Off unless set.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+        self.keepalive_deadline = keepalive.map(|keepalive| self.now + keepalive.idle);
+        self.keepalive_probes = 0;
     }
 
-    fn sock_recv(sock: &UdpSocket) -> Segment {
-        let mut buf = vec![0; (1 << 16) - 1];
-        let (amt, _) = sock.recv_from(&mut buf).unwrap();
-        buf.truncate(amt);
-        Segment::from_buf(buf)
+    fn send_syn(&mut self) {
+        let mut syn = self.make_seg();
+        syn.set_flag(Flag::SYN);
+        syn.set_seq(self.seq_base);
+        syn.set_window(self.advertised_window(true));
+        syn.set_options(self.local_opts.to_options());
+        if self.local_opts.timestamps {
+            syn.set_timestamps(self.ts_now(), 0);
+        }
+        self.send_seg(syn);
+        self.state = TCBState::SynSent;
     }
 
-    pub fn perform_handshake(
-        server_tuple: &mut TcbTup,
-        client_tuple: &mut TcbTup,
-        server_sock: &UdpSocket,
-        client_sock: &UdpSocket,
-    ) {
-        let (ref mut server_tcb, ref server_input, _) = *server_tuple;
-        let (ref mut client_tcb, ref client_input, _) = *client_tuple;
+    /// Swaps in another congestion control algorithm, must be called before the handshake
+    pub fn set_congestion_control(&mut self, cc: Box<dyn CongestionControl>) {
+        self.cc = cc;
+    }
 
-        client_input.send(TCBInput::SendSyn).unwrap();
-        client_tcb.handle_input_recv();
-        assert_eq!(client_tcb.state, TCBState::SynSent);
+    /// Replaces the RTO bounds, resetting any estimate made so far
+    pub fn set_rto_bounds(&mut self, min_rto: Duration, max_rto: Duration) {
+        self.rtt = RttEstimator::new(min_rto, max_rto);
+    }
 
-        let client_syn: Segment = sock_recv(&server_sock);
-        assert!(client_syn.get_flag(Flag::SYN));
-        server_input.send(TCBInput::Receive(client_syn)).unwrap();
-        server_tcb.handle_input_recv();
-        assert_eq!(server_tcb.state, TCBState::SynRecd);
+    /// How many times a SYN or SYN-ACK, and any later segment, is retransmitted before the
+    /// connection is given up on as timed out
+    pub fn set_retry_limits(&mut self, syn_retries: u32, data_retries: u32) {
+        self.syn_retries = syn_retries;
+        self.data_retries = data_retries;
+    }
 
-        let server_synack: Segment = sock_recv(&client_sock);
-        assert!(server_synack.get_flag(Flag::SYN));
-        assert!(server_synack.get_flag(Flag::ACK));
-        client_input.send(TCBInput::Receive(server_synack)).unwrap();
-        client_tcb.handle_input_recv();
-        assert_eq!(client_tcb.state, TCBState::Estab);
+    /// When the next unacknowledged segment is due to be retransmitted
+    fn rto_deadline(&self) -> Option<Instant> {
+        self.unacked_segs.iter().map(|sent| sent.deadline).min()
+    }
 
-        let client_ack: Segment = sock_recv(&server_sock);
-        assert!(client_ack.get_flag(Flag::ACK));
-        server_input.send(TCBInput::Receive(client_ack)).unwrap();
-        server_tcb.handle_input_recv();
-        assert_eq!(server_tcb.state, TCBState::Estab);
+    /// At least one unacknowledged segment went a whole RTO without an ACK, every segment that's
+    /// overdue is retransmitted at once rather than one per RTO
+    fn handle_rto(&mut self, now: Instant) {
+        let limit = match self.state {
+            TCBState::SynSent | TCBState::SynRecd => self.syn_retries,
+            _ => self.data_retries,
+        };
+        if self.retries >= limit {
+            self.close(Some(ConnectionError::TimedOut));
+            return;
+        }
+        self.retries += 1;
+        // The receiver is allowed to discard data it SACKed, so start over from the left edge
+        for sent in self.unacked_segs.iter_mut() {
+            sent.sacked = false;
+            sent.resent = false;
+        }
+        self.rtt.back_off();
+        self.cc.on_timeout(self.send_buffer.in_flight(), now);
+        // A burst sent back to back is due at almost the same moment, and shouldn't back off once
+        // for every segment in it
+        let due = now + Duration::from_millis(TIMER_SLACK);
+        for i in 0..self.unacked_segs.len() {
+            if self.unacked_segs[i].deadline <= due {
+                self.retransmit(i, now);
+            }
+        }
+    }
+
+    fn handle_keepalive(&mut self, now: Instant) {
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return,
+        };
+        if !self.state.can_send() && !self.state.can_recv() {
+            self.keepalive_deadline = None;
+            return;
+        }
+        // Outstanding data means the retransmission timer is already waiting on the peer
+        if !self.unacked_segs.is_empty() {
+            self.keepalive_deadline = Some(now + keepalive.idle);
+            return;
+        }
+        if self.keepalive_probes >= keepalive.probes {
+            self.close(Some(ConnectionError::Unreachable));
+            return;
+        }
+
+        self.send_probe();
+        self.keepalive_probes += 1;
+        self.keepalive_deadline = Some(now + keepalive.interval);
+    }
+
+    /// Starts or stops the persist timer.  It runs while the peer's zero window holds back data
+    /// and nothing is in flight, since then no ACK is coming that would say the window opened.
+    fn update_persist(&mut self, now: Instant) {
+        let stalled = self.peer_window == 0 && self.state.can_send() &&
+            self.unacked_segs.is_empty() && self.send_buffer.unsent() > 0;
+        if !stalled {
+            self.persist_deadline = None;
+            self.persist_backoff = 0;
+            self.persist_unanswered = 0;
+        } else if self.persist_deadline.is_none() {
+            self.persist_deadline = Some(now + self.persist_interval());
+        }
+    }
+
+    fn persist_interval(&self) -> Duration {
+        let backoff = 1 << min(self.persist_backoff, 16);
+        min(self.rtt.rto() * backoff, Duration::from_secs(MAX_PERSIST))
+    }
+
+    /// Probes a zero window.  The peer stays as long as it keeps answering, however long its
+    /// application takes to read.
+    fn handle_persist(&mut self, now: Instant) {
+        if self.persist_unanswered >= self.data_retries {
+            self.close(Some(ConnectionError::TimedOut));
+            return;
+        }
+        self.send_probe();
+        self.persist_unanswered += 1;
+        self.persist_backoff += 1;
+        self.persist_deadline = Some(now + self.persist_interval());
+    }
+
+    /// Sends a byte the peer already has, which it answers with an ACK carrying its current
+    /// window without taking anything in
+    fn send_probe(&mut self) {
+        let mut probe = self.make_seg();
+        probe.set_flag(Flag::ACK);
+        probe.set_seq(self.seq_base.wrapping_sub(1));
+        probe.set_ack_num(self.ack_base);
+        probe.set_data(vec![0]);
+        self.send_ack(probe);
+    }
+
+    fn fill_send_window(&mut self) {
+        if !self.state.can_send() {
+            return;
+        }
+        let in_flight = self.send_buffer.in_flight();
+        let window = min(min(self.peer_window, self.send_window_size), self.cc.cwnd());
+        let mut send_amt = min(self.send_buffer.unsent(), window.saturating_sub(in_flight));
+        // Nagle's algorithm, while data is in flight only full segments go out and small writes
+        // queue up behind them until it's acknowledged
+        if !self.nodelay && in_flight > 0 {
+            send_amt -= send_amt % self.send_mss();
+        }
+        if send_amt == 0 {
+            return;
+        }
+        self.send_data(send_amt);
+    }
+
+    /// Sends the next `amt` bytes of the send buffer, an MSS at a time
+    fn send_data(&mut self, amt: usize) {
+        let mss = self.send_mss();
+        self.cc.on_send(amt, self.now);
+        let mut sent = 0;
+        while sent < amt {
+            let size = min(mss, amt - sent);
+            let seq = self.seq_base.wrapping_add(self.send_buffer.in_flight() as u32);
+            self.send_buffer.mark_sent(size);
+            self.transmit_data(seq, size);
+            self.track(seq, size as u32, None);
+            sent += size;
+        }
+        self.delayed_ack_bytes = 0;
+        self.ack_deadline = None;
+    }
+
+    /// Sends the `len` bytes from `seq` on straight out of the send buffer
+    fn transmit_data(&mut self, seq: u32, len: usize) {
+        let mut seg = self.make_seg();
+        seg.set_seq(seq);
+        // Piggyback an ACK of everything received, standing in for any ACK being delayed
+        seg.set_flag(Flag::ACK);
+        seg.set_ack_num(self.ack_base);
+        let offset = seq.wrapping_sub(self.seq_base) as usize;
+        let bytes = {
+            let (front, back) = self.send_buffer.view(offset, len);
+            seg.to_byte_vec_with(&[front, back])
+        };
+        self.transmit(&seg, bytes);
+    }
+
+    fn handle_seg(&mut self, seg: Segment) {
+        // println!("Got seg: {:?}", seg);
+        if seg.get_flag(Flag::RST) {
+            self.handle_reset(&seg);
+            return;
+        }
+        // Anything at all from the peer shows it's still there
+        if let Some(keepalive) = self.keepalive {
+            self.keepalive_deadline = Some(self.now + keepalive.idle);
+            self.keepalive_probes = 0;
+        }
+        if !self.handshake_ack_acceptable(&seg) {
+            // Most likely an old duplicate from a previous connection on this tuple, RFC 793
+            // has us answer it with a RST and carry on
+            if let Some(rst) = TCBCore::reset_reply(&self.tuple, &seg) {
+                self.send_ack(rst);
+            }
+            return;
+        }
+        if !self.paws_acceptable(&seg) {
+            // An old duplicate, the ACK tells the peer where we really are
+            self.send_ack_now(None);
+            return;
+        }
+        // RFC 793 answers anything outside the window with an ACK of where we really are, a
+        // retransmitted SYN-ACK most of all, since it means our ACK of it was lost.  A FIN we've
+        // already seen gets acknowledged by handle_fin.
+        let old_fin = seg.get_flag(Flag::FIN) &&
+            seg.seq_num().wrapping_add(seg.payload().len() as u32 + 1) == self.ack_base;
+        if self.state.is_synchronized() && !old_fin &&
+            (seg.get_flag(Flag::SYN) || !self.seq_acceptable(&seg))
+        {
+            // A shut window can't take the peer's data or FIN, but the ACK riding on them still
+            // counts, or both ends stall waiting on each other
+            let seg_end = seg.seq_num().wrapping_add(seg.payload().len() as u32);
+            if !seg.get_flag(Flag::SYN) && self.recv_window() == 0 &&
+                seq_geq(self.ack_base, seg.seq_num()) && seq_geq(seg_end, self.ack_base)
+            {
+                self.update_ts_recent(&seg);
+                self.handle_acks(&seg);
+            }
+            self.send_ack_now(None);
+            return;
+        }
+        self.update_ts_recent(&seg);
+        self.handle_acks(&seg); // sender
+        self.handle_shake(&seg);
+        self.handle_payload(&seg); // receiver
+        if seg.get_flag(Flag::FIN) {
+            self.handle_fin(&seg);
+        }
+    }
+
+    /// PAWS (RFC 7323), a segment stamped before the latest one we've seen is an old duplicate,
+    /// even when its sequence number has wrapped back into the window
+    fn paws_acceptable(&self, seg: &Segment) -> bool {
+        if !self.ts_enabled() || seg.get_flag(Flag::SYN) {
+            return true;
+        }
+        match (seg.timestamps(), self.ts_recent) {
+            (Some((val, _)), Some((recent, at))) => {
+                seq_geq(val, recent) ||
+                    self.now.duration_since(at) > Duration::from_secs(PAWS_IDLE)
+            }
+            _ => true,
+        }
+    }
+
+    /// Only a segment starting at the left edge of the window may update the timestamp we echo,
+    /// so an ACK covering several segments echoes the earliest of them
+    fn update_ts_recent(&mut self, seg: &Segment) {
+        if let Some((val, _)) = seg.timestamps() {
+            if seg.get_flag(Flag::SYN) || seq_geq(self.ack_base, seg.seq_num()) {
+                self.ts_recent = Some((val, self.now));
+            }
+        }
+    }
+
+    /// RFC 793's acceptance test, whether any of the segment falls inside the receive window
+    fn seq_acceptable(&self, seg: &Segment) -> bool {
+        let len = seg.seq_len();
+        let window = self.recv_window() as u32;
+        let window_end = self.ack_base.wrapping_add(window);
+        let in_window = |seq| in_wrapped_range((self.ack_base, window_end), seq);
+        match (len, window) {
+            (0, 0) => seg.seq_num() == self.ack_base,
+            (0, _) => in_window(seg.seq_num()),
+            (_, 0) => false,
+            _ => in_window(seg.seq_num()) || in_window(seg.seq_num().wrapping_add(len - 1)),
+        }
+    }
+
+    /// While handshaking the only acceptable ACK is of our SYN, which took up the ISN
+    fn handshake_ack_acceptable(&self, seg: &Segment) -> bool {
+        match self.state {
+            TCBState::SynSent | TCBState::SynRecd if seg.get_flag(Flag::ACK) => {
+                seg.ack_num() == self.seq_base.wrapping_add(1)
+            }
+            _ => true,
+        }
+    }
+
+    fn handle_payload(&mut self, seg: &Segment) {
+        if !self.state.can_recv() {
+            return;
+        }
+        if seg.payload().is_empty() {
+            return;
+        }
+        // Only buffer what fits in the window we advertised, the rest gets resent later
+        let had_gap = !self.reassembly.is_empty();
+        self.reassembly.insert(
+            self.ack_base,
+            self.recv_window(),
+            seg.seq_num(),
+            &seg.payload()[..],
+        );
+        let in_order = seg.seq_num() == self.ack_base;
+        if in_order {
+            self.delayed_ack_bytes += seg.payload().len();
+        }
+        if let Some(data) = self.reassembly.pop(self.ack_base) {
+            self.ack_base = self.ack_base.wrapping_add(data.len() as u32);
+            self.unread += data.len();
+            self.actions.deliver.extend(data);
+        }
+        // Out of order data is acknowledged straight away, the duplicate ACK and its SACK blocks
+        // tell the sender what's missing, and so is data filling a hole.  Otherwise every second
+        // full segment gets an ACK, a lone one waits a little for company.
+        let full_segs = 2 * self.local_opts.mss as usize;
+        if !in_order || had_gap || self.delayed_ack_bytes >= full_segs ||
+            self.ack_delay == Duration::from_secs(0)
+        {
+            self.send_ack_now(Some(seg.seq_num()));
+        } else if self.ack_deadline.is_none() {
+            self.ack_deadline = Some(self.now + self.ack_delay);
+        }
+    }
+
+    /// Ranges of out of order data to report, the one holding `recent` first as RFC 2018 asks
+    fn sack_blocks(&self, recent: Option<u32>) -> Vec<(u32, u32)> {
+        if self.reassembly.is_empty() || !self.sack_enabled() {
+            return vec![];
+        }
+        let mut blocks = self.reassembly.ranges();
+        if let Some(seq) = recent {
+            if let Some(i) = blocks.iter().position(|&block| in_wrapped_range(block, seq)) {
+                let block = blocks.remove(i);
+                blocks.insert(0, block);
+            }
+        }
+        blocks.truncate(MAX_SACK_BLOCKS);
+        blocks
+    }
+
+    fn handle_acks(&mut self, seg: &Segment) {
+        let old_window = self.peer_window;
+        let max_flight = self.send_window_size as u32;
+        if let Some(window) = seg.window() {
+            // The window in a SYN is never scaled, but a stray one once we're synchronized
+            // mustn't undo the scaling
+            if seg.get_flag(Flag::SYN) {
+                if !self.state.is_synchronized() {
+                    self.peer_window = window as usize;
+                    self.snd_wl1 = seg.seq_num();
+                    self.snd_wl2 = seg.ack_num();
+                }
+            } else if seg.get_flag(Flag::ACK) &&
+                       in_wrapped_range(
+                    (self.seq_base, self.seq_base.wrapping_add(max_flight + 1)),
+                    seg.ack_num(),
+                )
+            {
+                // RFC 793's SND.WL1/SND.WL2 check, a segment older than the one the window last
+                // came from mustn't undo it when the two arrive out of order
+                let newer = !seq_geq(self.snd_wl1, seg.seq_num()) ||
+                    (self.snd_wl1 == seg.seq_num() && seq_geq(seg.ack_num(), self.snd_wl2));
+                if newer {
+                    self.peer_window = (window as usize) << self.send_shift();
+                    self.snd_wl1 = seg.seq_num();
+                    self.snd_wl2 = seg.ack_num();
+                }
+            }
+        }
+
+        if seg.get_flag(Flag::ACK) {
+            self.persist_unanswered = 0;
+            if self.sack_enabled() {
+                self.mark_sacked(seg.sack_blocks());
+            }
+        }
+
+        let ack_lb = self.seq_base.wrapping_add(1);
+        let ack_ub = ack_lb.wrapping_add(max_flight);
+        let new_ack = seg.get_flag(Flag::ACK) && in_wrapped_range((ack_lb, ack_ub), seg.ack_num());
+        if new_ack {
+            let now = self.now;
+            // A segment the ACK only covers part of stays, the rest of it may still be lost
+            let fully_acked = self.unacked_segs
+                .iter()
+                .take_while(|unacked| {
+                    let end = unacked.seq.wrapping_add(unacked.len);
+                    in_wrapped_range((ack_lb, seg.ack_num().wrapping_add(1)), end)
+                })
+                .count();
+            let acked: Vec<SentSeg> = self.unacked_segs.drain(..fully_acked).collect();
+            // Its acknowledged front is gone from the send buffer, only the rest can be resent
+            if let Some(partly_acked) = self.unacked_segs.front_mut() {
+                let end = partly_acked.seq.wrapping_add(partly_acked.len);
+                if partly_acked.control.is_none() &&
+                    in_wrapped_range((partly_acked.seq, end), seg.ack_num())
+                {
+                    partly_acked.len = end.wrapping_sub(seg.ack_num());
+                    partly_acked.seq = seg.ack_num();
+                }
+            }
+            if self.ts_enabled() {
+                // The echoed timestamp says which transmission this answers, so every ACK gives
+                // a sample, retransmitted or not
+                if let Some((_, ecr)) = seg.timestamps() {
+                    let rtt = self.ts_now().wrapping_sub(ecr);
+                    self.rtt.sample(Duration::from_millis(rtt as u64));
+                }
+            } else if !acked.iter().any(|sent| sent.retransmitted) {
+                if let Some(newest) = acked.last() {
+                    self.rtt.sample(now.duration_since(newest.sent_at));
+                }
+            }
+            // The peer is making progress, so nothing still out is overdue for another RTO
+            let restarted = now + self.rtt.rto();
+            for sent in self.unacked_segs.iter_mut() {
+                sent.deadline = max(sent.deadline, restarted);
+            }
+
+            // The SYN and FIN each take up a sequence number without being in the send window
+            let num_acked_bytes = self.send_buffer.ack(
+                seg.ack_num().wrapping_sub(self.seq_base) as usize,
+            );
+            self.seq_base = seg.ack_num();
+
+            self.retries = 0;
+            self.dupe_acks = 0;
+            let in_flight = self.send_buffer.in_flight();
+            if self.cc.on_ack(seg.ack_num(), num_acked_bytes, in_flight, now) {
+                self.retransmit_lost();
+            }
+
+            if self.fin_seq.map(|fin_seq| fin_seq.wrapping_add(1)) == Some(seg.ack_num()) {
+                self.handle_fin_acked();
+            } else {
+                self.fill_send_window();
+                self.try_send_fin();
+            }
+        }
+
+        if !new_ack && self.is_dupe_ack(seg, old_window) {
+            self.dupe_acks += 1;
+            let flight = self.send_buffer.in_flight();
+            let snd_nxt = self.seq_base.wrapping_add(flight as u32);
+            if self.cc.on_dup_ack(self.dupe_acks, snd_nxt, flight, self.now) {
+                self.retransmit_lost();
+                // println!("\x1b[31m Triple Duplicate ACK! Resending \x1b[0m");
+            }
+            // Recovery may have opened the window
+            self.fill_send_window();
+        }
+
+        // A window update, perhaps the answer to a zero window probe
+        if !new_ack && self.peer_window > old_window {
+            self.fill_send_window();
+        }
+    }
+
+    fn mark_sacked(&mut self, blocks: &[(u32, u32)]) {
+        for sent in self.unacked_segs.iter_mut() {
+            let end = sent.seq.wrapping_add(sent.len);
+            if blocks.iter().any(|&(left, right)| seq_geq(sent.seq, left) && seq_geq(right, end)) {
+                sent.sacked = true;
+            }
+        }
+    }
+
+    fn handle_shake(&mut self, seg: &Segment) {
+        match self.state {
+            TCBState::Listen if seg.get_flag(Flag::SYN) => {
+                self.state = TCBState::SynRecd;
+                self.ack_base = seg.seq_num().wrapping_add(1);
+                self.peer_opts = SynOptions::from_segment(seg);
+                self.cc.set_mss(self.send_mss());
+                let mut synack = self.make_seg();
+                synack.set_flag(Flag::SYN);
+                synack.set_flag(Flag::ACK);
+                synack.set_seq(self.seq_base);
+                synack.set_ack_num(self.ack_base);
+                if let Some(peer) = self.peer_opts {
+                    synack.set_window(self.advertised_window(true));
+                    synack.set_options(self.local_opts.answer(&peer).to_options());
+                    self.stamp(&mut synack);
+                }
+                self.send_seg(synack);
+            }
+            TCBState::SynSent if seg.get_flag(Flag::SYN) && seg.get_flag(Flag::ACK) => {
+                self.state = TCBState::Estab;
+                self.ack_base = seg.seq_num().wrapping_add(1);
+                self.peer_opts = SynOptions::from_segment(seg);
+                self.cc.set_mss(self.send_mss());
+                let mut ack = self.make_seg();
+                ack.set_flag(Flag::ACK);
+                ack.set_ack_num(self.ack_base);
+                self.send_ack(ack);
+                self.fill_send_window();
+            }
+            // handle_seg already checked this acknowledges our SYN
+            TCBState::SynRecd if seg.get_flag(Flag::ACK) => {
+                self.state = TCBState::Estab;
+                self.fill_send_window();
+                self.try_send_fin();
+            }
+            _ => {}
+        }
+    }
+
+
+    fn handle_fin(&mut self, seg: &Segment) {
+        // Only act on the FIN once everything before it has arrived, the peer will resend it
+        let fin_seq = seg.seq_num().wrapping_add(seg.payload().len() as u32);
+        if fin_seq == self.ack_base && self.state.can_recv() {
+            self.ack_base = self.ack_base.wrapping_add(1);
+            // Everything the peer will ever send has been delivered
+            self.finish(None);
+            self.state = match self.state {
+                TCBState::Estab => TCBState::CloseWait,
+                TCBState::FinWait1 => TCBState::Closing,
+                _ => TCBState::TimeWait,
+            };
+        } else if fin_seq.wrapping_add(1) != self.ack_base {
+            return;
+        }
+
+        // Either a new FIN or a retransmission because our ACK of it was lost
+        self.send_ack_now(None);
+        if self.state == TCBState::TimeWait {
+            self.time_wait_until = Some(self.now + self.time_wait);
+        }
+    }
+
+    fn handle_fin_acked(&mut self) {
+        match self.state {
+            TCBState::FinWait1 => self.state = TCBState::FinWait2,
+            TCBState::Closing => {
+                self.state = TCBState::TimeWait;
+                self.time_wait_until = Some(self.now + self.time_wait);
+            }
+            TCBState::LastAck => self.close(None),
+            _ => {}
+        }
+    }
+
+    fn send_close(&mut self) {
+        match self.state {
+            TCBState::Listen | TCBState::SynSent => self.close(None),
+            _ => {
+                self.close_requested = true;
+                self.try_send_fin();
+            }
+        }
+    }
+
+    /// Sends our FIN once the application has closed and every byte before it is acknowledged
+    fn try_send_fin(&mut self) {
+        if !self.close_requested || self.fin_seq.is_some() || !self.state.can_send() ||
+            !self.send_buffer.is_empty()
+        {
+            return;
+        }
+        let mut fin = self.make_seg();
+        fin.set_flag(Flag::FIN);
+        fin.set_flag(Flag::ACK);
+        fin.set_seq(self.seq_base);
+        fin.set_ack_num(self.ack_base);
+        self.fin_seq = Some(self.seq_base);
+        self.send_seg(fin);
+        self.state = match self.state {
+            TCBState::CloseWait => TCBState::LastAck,
+            _ => TCBState::FinWait1,
+        };
+    }
+
+    fn handle_reset(&mut self, seg: &Segment) {
+        // A RST is only believed if it lines up with this connection, otherwise anyone who
+        // knows the ports could kill it
+        let acceptable = match self.state {
+            // RFC 1337, a stray RST mustn't cut TIME-WAIT short or spoil a clean close
+            TCBState::Listen | TCBState::TimeWait | TCBState::Closed => false,
+            TCBState::SynSent => {
+                seg.get_flag(Flag::ACK) && seg.ack_num() == self.seq_base.wrapping_add(1)
+            }
+            _ => {
+                in_wrapped_range(
+                    (self.ack_base, self.ack_base.wrapping_add(self.recv_capacity as u32)),
+                    seg.seq_num(),
+                )
+            }
+        };
+        if acceptable {
+            self.close(Some(ConnectionError::Reset));
+        }
+    }
+
+    fn abort(&mut self) {
+        if self.state != TCBState::Listen && self.state != TCBState::Closed {
+            // Numbered past our FIN if that's gone out, where the peer expects the next segment
+            let mut rst = self.make_seg();
+            rst.set_flag(Flag::RST);
+            rst.set_flag(Flag::ACK);
+            rst.set_ack_num(self.ack_base);
+            self.send_ack(rst);
+        }
+        self.close(Some(ConnectionError::Aborted));
+    }
+
+    /// Moves to `Closed` and hangs up on the application, recording why if it wasn't a clean close
+    fn close(&mut self, err: Option<ConnectionError>) {
+        self.state = TCBState::Closed;
+        self.finish(err);
+        if err.is_some() {
+            self.send_buffer.clear();
+            self.unacked_segs.clear();
+            self.ack_deadline = None;
+            self.keepalive_deadline = None;
+        }
+    }
+
+    fn handle_resend(&mut self) {
+        if !self.unacked_segs.is_empty() {
+            let now = self.now;
+            self.retransmit(0, now);
+        }
+    }
+
+    /// Resends the `i`th unacknowledged segment, restarting its timer
+    fn retransmit(&mut self, i: usize, now: Instant) {
+        // Data is cut afresh and gets a new timestamp along with it, a SYN or FIN is restamped
+        let timestamps = self.timestamps();
+        if let Some(ref mut seg) = self.unacked_segs[i].control {
+            if let Some((val, ecr)) = timestamps {
+                seg.set_timestamps(val, ecr);
+            }
+        }
+        let sent = &self.unacked_segs[i];
+        match sent.control.clone() {
+            Some(seg) => self.resend_seg(&seg),
+            None => self.transmit_data(sent.seq, sent.len as usize),
+        }
+        let rto = self.rtt.rto();
+        let sent = &mut self.unacked_segs[i];
+        sent.sent_at = now;
+        sent.deadline = now + rto;
+        sent.retransmitted = true;
+    }
+
+    /// A duplicate ACK as RFC 5681 defines it: it acknowledges nothing new while data is
+    /// outstanding, carries no data, no SYN or FIN, and leaves the window alone.  Anything else
+    /// says nothing about loss.
+    fn is_dupe_ack(&self, seg: &Segment, old_window: usize) -> bool {
+        seg.get_flag(Flag::ACK) && !seg.get_flag(Flag::SYN) && !seg.get_flag(Flag::FIN) &&
+            seg.payload().is_empty() && seg.ack_num() == self.seq_base &&
+            !self.unacked_segs.is_empty() &&
+            seg.window().is_none_or(|window| {
+                ((window as usize) << self.send_shift()) == old_window
+            })
+    }
+
+    /// Fast retransmit.  Once the peer has SACKed something every hole before it is resent, but
+    /// only once, and the SACKed segments are left alone.
+    fn retransmit_lost(&mut self) {
+        let last_sacked = match self.unacked_segs.iter().rposition(|sent| sent.sacked) {
+            Some(last_sacked) => last_sacked,
+            None => return self.handle_resend(),
+        };
+        let holes: Vec<usize> = (0..last_sacked)
+            .filter(|&i| !self.unacked_segs[i].sacked && !self.unacked_segs[i].resent)
+            .collect();
+        let now = self.now;
+        for &i in &holes {
+            self.retransmit(i, now);
+            self.unacked_segs[i].resent = true;
+        }
+    }
+
+    fn handle_read(&mut self, amt: usize) {
+        self.unread = self.unread.saturating_sub(amt);
+        if !self.state.can_recv() {
+            return;
+        }
+        // Receiver side silly window avoidance, only announce growth of at least an MSS or half
+        // the buffer, whichever is smaller
+        let threshold = min(MAX_PAYLOAD_SIZE, self.recv_capacity / 2);
+        if self.recv_window() >= self.advertised + threshold {
+            self.send_ack_now(None);
+        }
+    }
+
+    /// Nothing more will be delivered, the application is told why once it has read the rest.
+    /// An error replaces a clean end, but not the other way round.
+    fn finish(&mut self, err: Option<ConnectionError>) {
+        if err.is_some() || self.actions.end.is_none() {
+            self.actions.end = Some(err.unwrap_or(ConnectionError::Closed));
+        }
+    }
+
+    /// A SYN-ACK whose sequence number is a SYN cookie, so the SYN can be answered without a TCB.
+    /// The window is a `recv_window` byte receive buffer's, as a TCB would advertise in its
+    /// SYN-ACK.  Only the MSS fits in the cookie, so window scaling, SACK and timestamps are
+    /// declined on purpose, a connection made from a cookie can't remember agreeing to them.
+    pub fn syn_cookie_reply(tuple: &TCPTuple, syn: &Segment, recv_window: usize) -> Segment {
+        let peer_opts = SynOptions::from_segment(syn);
+        let mss = peer_opts.map_or(MAX_PAYLOAD_SIZE as u16, |peer| peer.mss);
+        let mut synack = Segment::new(tuple.src.port(), tuple.dst.port());
+        synack.set_flag(Flag::SYN);
+        synack.set_flag(Flag::ACK);
+        synack.set_seq(syncookie::encode(tuple, syn.seq_num(), mss));
+        synack.set_ack_num(syn.seq_num().wrapping_add(1));
+        if peer_opts.is_some() {
+            synack.set_window(window_field(recv_window, 0));
+            synack.set_options(vec![SegmentOption::MaxSegmentSize(MAX_PAYLOAD_SIZE as u16)]);
+        }
+        synack
+    }
+
+    /// Recreates the connection a SYN cookie stood for from the ACK that completes its handshake,
+    /// `None` if the ACK doesn't carry a valid cookie.  `window_sizes` are as for
+    /// `set_window_sizes`, with the receive size the one the SYN-ACK advertised.
+    pub fn from_syn_cookie(
+        tuple: TCPTuple,
+        ack: &Segment,
+        (send, recv): (usize, usize),
+        now: Instant,
+    ) -> Option<TCBCore> {
+        if !ack.get_flag(Flag::ACK) || ack.get_flag(Flag::SYN) || ack.get_flag(Flag::RST) {
+            return None;
+        }
+        let peer_isn = ack.seq_num().wrapping_sub(1);
+        let cookie = ack.ack_num().wrapping_sub(1);
+        let mss = syncookie::decode(&tuple, peer_isn, cookie)?;
+
+        let mut tcb = TCBCore::new(tuple, now);
+        tcb.set_window_sizes(send, recv);
+        tcb.state = TCBState::Estab;
+        tcb.seq_base = ack.ack_num();
+        tcb.ack_base = ack.seq_num();
+        // As if the window had come from the SYN, so the ACK's own window is taken
+        tcb.snd_wl1 = peer_isn;
+        tcb.snd_wl2 = cookie;
+        // Only peers that understand options advertise a window
+        tcb.peer_opts = ack.window().map(|_| {
+            SynOptions {
+                mss,
+                window_scale: None,
+                sack_permitted: false,
+                timestamps: false,
+            }
+        });
+        tcb.cc.set_mss(tcb.send_mss());
+        Some(tcb)
+    }
+
+    /// Builds the RST that answers a segment which belongs to no connection, per RFC 793
+    pub fn reset_reply(tuple: &TCPTuple, seg: &Segment) -> Option<Segment> {
+        if seg.get_flag(Flag::RST) {
+            return None;
+        }
+        let mut rst = Segment::new(tuple.src.port(), tuple.dst.port());
+        rst.set_flag(Flag::RST);
+        if seg.get_flag(Flag::ACK) {
+            rst.set_seq(seg.ack_num());
+        } else {
+            let mut seg_len = seg.payload().len() as u32;
+            if seg.get_flag(Flag::SYN) {
+                seg_len += 1;
+            }
+            if seg.get_flag(Flag::FIN) {
+                seg_len += 1;
+            }
+            rst.set_flag(Flag::ACK);
+            rst.set_ack_num(seg.seq_num().wrapping_add(seg_len));
+        }
+        Some(rst)
+    }
+
+    fn make_seg(&self) -> Segment {
+        let mut seg = Segment::new(self.tuple.src.port(), self.tuple.dst.port());
+        // Our FIN takes up a sequence number too, nothing after it may reuse that
+        let nxt = match self.fin_seq {
+            Some(fin_seq) => fin_seq.wrapping_add(1),
+            None => self.seq_base.wrapping_add(self.send_buffer.in_flight() as u32),
+        };
+        seg.set_seq(nxt);
+        // Legacy peers don't understand the options area, so they never get a window
+        if self.peer_opts.is_some() {
+            seg.set_window(self.advertised_window(false));
+        }
+        self.stamp(&mut seg);
+        seg
+    }
+
+    /// An ACK of everything received in order, with SACK blocks for anything after a hole
+    fn make_ack(&self, recent: Option<u32>) -> Segment {
+        let mut ack = self.make_seg();
+        ack.set_flag(Flag::ACK);
+        ack.set_ack_num(self.ack_base);
+        let blocks = self.sack_blocks(recent);
+        if !blocks.is_empty() {
+            let mut opts = ack.options().to_vec();
+            opts.push(SegmentOption::Sack(blocks));
+            ack.set_options(opts);
+        }
+        ack
+    }
+
+    /// Sends a SYN or FIN, keeping it to resend until it's acknowledged
+    fn send_seg(&mut self, seg: Segment) {
+        self.resend_seg(&seg);
+        self.track(seg.seq_num(), seg.seq_len(), Some(seg));
+    }
+
+    fn track(&mut self, seq: u32, len: u32, control: Option<Segment>) {
+        let now = self.now;
+        self.unacked_segs.push_back(SentSeg {
+            seq,
+            len,
+            control,
+            sent_at: now,
+            deadline: now + self.rtt.rto(),
+            retransmitted: false,
+            sacked: false,
+            resent: false,
+        });
+    }
+
+    /// Acknowledges everything received so far, which covers any ACK being delayed
+    fn send_ack_now(&mut self, recent: Option<u32>) {
+        let ack = self.make_ack(recent);
+        self.send_ack(ack);
+        self.delayed_ack_bytes = 0;
+        self.ack_deadline = None;
+    }
+
+    fn send_ack(&mut self, seg: Segment) {
+        self.resend_seg(&seg);
+    }
+
+    fn resend_seg(&mut self, seg: &Segment) {
+        self.transmit(seg, seg.to_byte_vec());
+    }
+
+    /// Queues `bytes`, the encoding of `seg` along with any payload, to be sent
+    fn transmit(&mut self, seg: &Segment, bytes: Vec<u8>) {
+        if let Some(window) = seg.window() {
+            let shift = if seg.get_flag(Flag::SYN) { 0 } else { self.recv_shift() };
+            self.advertised = (window as usize) << shift;
+        }
+        self.actions.transmit.push(bytes);
+    }
+}
+
+/// Runs a `TCBCore` on its own thread, talking to the peer over a UDP socket and to the
+/// application through `TCBInput` messages and a `TCBOutput`.  It derefs to the core, so it's
+/// configured and inspected like one.
+#[derive(Debug)]
+pub struct TCB {
+    core: TCBCore,
+    socket: UdpSocket,
+    data_input: Receiver<TCBInput>,
+    recv_buffer: Arc<RecvBuffer>,
+}
+
+impl TCB {
+    pub fn new(tuple: TCPTuple, udp_sock: UdpSocket) -> (TCB, Sender<TCBInput>, TCBOutput) {
+        TCB::with_core(TCBCore::new(tuple, Instant::now()), udp_sock)
+    }
+
+    /// Recreates the connection a SYN cookie stood for, see `TCBCore::from_syn_cookie`
+    pub fn from_syn_cookie(
+        tuple: TCPTuple,
+        udp_sock: UdpSocket,
+        ack: &Segment,
+        window_sizes: (usize, usize),
+    ) -> Option<(TCB, Sender<TCBInput>, TCBOutput)> {
+        TCBCore::from_syn_cookie(tuple, ack, window_sizes, Instant::now())
+            .map(|core| TCB::with_core(core, udp_sock))
+    }
+
+    fn with_core(core: TCBCore, udp_sock: UdpSocket) -> (TCB, Sender<TCBInput>, TCBOutput) {
+        let (data_input_tx, data_input_rx) = channel();
+        let recv_buffer = Arc::new(RecvBuffer::default());
+        let output = TCBOutput {
+            buffer: recv_buffer.clone(),
+            input: data_input_tx.clone(),
+        };
+        let tcb = TCB {
+            core,
+            socket: udp_sock,
+            data_input: data_input_rx,
+            recv_buffer,
+        };
+        (tcb, data_input_tx, output)
+    }
+
+    /// Reads exactly `amt` bytes, waiting for as many as it takes
+    pub fn recv(out: &TCBOutput, amt: u32) -> Result<Vec<u8>, ConnectionError> {
+        let mut buf = vec![0; amt as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += out.read(&mut buf[filled..])?;
+        }
+        Ok(buf)
+    }
+
+    pub fn run_tcp(&mut self) {
+        while self.core.state != TCBState::Closed {
+            self.handle_input_recv();
+        }
+    }
+
+    /// Waits for the next input or timer and hands it to the core
+    fn handle_input_recv(&mut self) {
+        let mut timeout = Duration::from_secs(TIMEOUT);
+        if let Some(deadline) = self.core.deadline() {
+            timeout = min(timeout, deadline.saturating_duration_since(Instant::now()));
+        }
+        let actions = match self.data_input.recv_timeout(timeout) {
+            Ok(input) => {
+                let now = Instant::now();
+                match input {
+                    TCBInput::SendSyn => self.core.on_app_connect(now),
+                    TCBInput::Receive(seg) => self.core.on_segment(seg, now),
+                    TCBInput::Send(data) => self.core.on_app_write(&data, now),
+                    TCBInput::Close => self.core.on_app_close(now),
+                    TCBInput::Abort => self.core.on_app_abort(now),
+                    TCBInput::WindowUpdate => {
+                        self.recv_buffer.update_pending.store(false, Ordering::SeqCst);
+                        let amt = self.recv_buffer.consumed.swap(0, Ordering::SeqCst);
+                        self.core.on_app_read(amt, now)
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => TCBActions::default(),
+            Err(e) => panic!("{}", e),
+        };
+        self.apply(actions);
+        let actions = self.core.on_timeout(Instant::now());
+        self.apply(actions);
+    }
+
+    fn apply(&self, actions: TCBActions) {
+        for bytes in actions.transmit {
+            self.socket.send_to(&bytes[..], self.core.tuple.dst).unwrap();
+        }
+        if !actions.deliver.is_empty() {
+            self.recv_buffer.push(&actions.deliver);
+        }
+        if let Some(end) = actions.end {
+            self.recv_buffer.finish(end);
+        }
+    }
+}
+
+impl Deref for TCB {
+    type Target = TCBCore;
+
+    fn deref(&self) -> &TCBCore {
+        &self.core
+    }
+}
+
+impl DerefMut for TCB {
+    fn deref_mut(&mut self) -> &mut TCBCore {
+        &mut self.core
+    }
+}
+
+impl Drop for TCB {
+    fn drop(&mut self) {
+        // Don't leave the application waiting on data that will never come
+        self.recv_buffer.finish(ConnectionError::Closed);
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::thread;
+    use sim::{LinkConfig, Network};
+
+    type TcbTup = (TCB, Sender<TCBInput>, TCBOutput);
+    pub fn tcb_pair() -> (TcbTup, TcbTup, UdpSocket, UdpSocket) {
+        let server_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_tuple = TCPTuple {
+            src: server_sock.local_addr().unwrap(),
+            dst: client_sock.local_addr().unwrap(),
+        };
+        let client_tuple = TCPTuple {
+            src: client_sock.local_addr().unwrap(),
+            dst: server_sock.local_addr().unwrap(),
+        };
+        let server_tuple = TCB::new(server_tuple, server_sock.try_clone().unwrap());
+        let client_tuple = TCB::new(client_tuple, client_sock.try_clone().unwrap());
+        (server_tuple, client_tuple, server_sock, client_sock)
+    }
+
+    fn sock_recv(sock: &UdpSocket) -> Segment {
+        let mut buf = vec![0; (1 << 16) - 1];
+        let (amt, _) = sock.recv_from(&mut buf).unwrap();
+        Segment::parse(&buf[..amt]).unwrap()
+    }
+
+    pub fn perform_handshake(
+        server_tuple: &mut TcbTup,
+        client_tuple: &mut TcbTup,
+        server_sock: &UdpSocket,
+        client_sock: &UdpSocket,
+    ) {
+        let (ref mut server_tcb, ref server_input, _) = *server_tuple;
+        let (ref mut client_tcb, ref client_input, _) = *client_tuple;
+
+        client_input.send(TCBInput::SendSyn).unwrap();
+        client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::SynSent);
+
+        let client_syn: Segment = sock_recv(server_sock);
+        assert!(client_syn.get_flag(Flag::SYN));
+        server_input.send(TCBInput::Receive(client_syn)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::SynRecd);
+
+        let server_synack: Segment = sock_recv(client_sock);
+        assert!(server_synack.get_flag(Flag::SYN));
+        assert!(server_synack.get_flag(Flag::ACK));
+        client_input.send(TCBInput::Receive(server_synack)).unwrap();
+        client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::Estab);
+
+        let client_ack: Segment = sock_recv(server_sock);
+        assert!(client_ack.get_flag(Flag::ACK));
+        server_input.send(TCBInput::Receive(client_ack)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::Estab);
+    }
+
+    #[test]
+    fn full_handshake() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+    }
+
+    #[test]
+    fn handshake_negotiates_options() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_syn_options(SynOptions {
+            mss: 1000,
+            window_scale: None,
+            sack_permitted: true,
+            timestamps: false,
+        });
+        server_tuple.0.set_syn_options(SynOptions {
+            sack_permitted: false,
+            ..SynOptions::default()
+        });
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+
+        let server_opts = server_tuple.0.negotiated_options().unwrap();
+        let client_opts = client_tuple.0.negotiated_options().unwrap();
+        assert_eq!(server_tuple.0.send_mss(), 1000);
+        assert_eq!(client_tuple.0.send_mss(), MAX_PAYLOAD_SIZE);
+        assert_eq!(server_opts.window_scale, None);
+        assert_eq!(client_opts.window_scale, None);
+        assert!(!server_opts.sack_permitted);
+        assert!(!client_opts.sack_permitted);
+    }
+
+    #[test]
+    fn handshake_checks_ack() {
+        let (server_tuple, client_tuple, server_sock, client_sock) = tcb_pair();
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _) = client_tuple;
+        client_input.send(TCBInput::SendSyn).unwrap();
+        client_tcb.handle_input_recv();
+        let syn = sock_recv(&server_sock);
+        assert_eq!(syn.seq_num(), client_tcb.seq_base);
+
+        // A SYN-ACK for some other ISN, say from an earlier connection, gets reset
+        let mut stale = server_tcb.make_seg();
+        stale.set_flag(Flag::SYN);
+        stale.set_flag(Flag::ACK);
+        stale.set_ack_num(syn.seq_num().wrapping_add(1000));
+        client_input.send(TCBInput::Receive(stale)).unwrap();
+        client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::SynSent);
+        let rst = sock_recv(&server_sock);
+        assert!(rst.get_flag(Flag::RST));
+        assert_eq!(rst.seq_num(), syn.seq_num().wrapping_add(1000));
+
+        // Likewise the final ACK of the handshake
+        server_input.send(TCBInput::Receive(syn)).unwrap();
+        server_tcb.handle_input_recv();
+        let synack = sock_recv(&client_sock);
+        assert_eq!(synack.seq_num(), server_tcb.seq_base);
+        assert_ne!(synack.seq_num(), client_tcb.seq_base);
+        let mut bad_ack = client_tcb.make_seg();
+        bad_ack.set_flag(Flag::ACK);
+        bad_ack.set_ack_num(synack.seq_num());
+        server_input.send(TCBInput::Receive(bad_ack)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::SynRecd);
+        assert!(sock_recv(&client_sock).get_flag(Flag::RST));
+
+        client_input.send(TCBInput::Receive(synack)).unwrap();
+        client_tcb.handle_input_recv();
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(client_tcb.state, TCBState::Estab);
+        assert_eq!(server_tcb.state, TCBState::Estab);
+    }
+
+    #[test]
+    fn syn_cookie_handshake() {
+        let (server_tuple, client_tuple, server_sock, client_sock) = tcb_pair();
+        let (listener, _, _) = server_tuple;
+        let (mut client_tcb, client_input, _) = client_tuple;
+        client_tcb.set_ack_delay(Duration::from_secs(0));
+        client_input.send(TCBInput::SendSyn).unwrap();
+        client_tcb.handle_input_recv();
+
+        // Answer without keeping any state around
+        let syn = sock_recv(&server_sock);
+        let synack = TCBCore::syn_cookie_reply(&listener.tuple, &syn, WINDOW_SIZE);
+        assert_eq!(synack.ack_num(), syn.seq_num().wrapping_add(1));
+        assert_eq!(synack.window(), Some(listener.advertised_window(true)));
+        let big = TCBCore::syn_cookie_reply(&listener.tuple, &syn, 1 << 20);
+        assert_eq!(big.window(), Some(u16::MAX));
+        assert_eq!(synack.options(), &[SegmentOption::MaxSegmentSize(MAX_PAYLOAD_SIZE as u16)]);
+        server_sock
+            .send_to(&synack.to_byte_vec(), listener.tuple.dst)
+            .unwrap();
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(client_tcb.state, TCBState::Estab);
+
+        let ack = sock_recv(&server_sock);
+        let mut forged = ack.clone();
+        forged.set_ack_num(ack.ack_num().wrapping_add(1));
+        let sock = || server_sock.try_clone().unwrap();
+        let sizes = (WINDOW_SIZE, WINDOW_SIZE);
+        assert!(TCB::from_syn_cookie(listener.tuple, sock(), &forged, sizes).is_none());
+
+        let (mut server_tcb, server_input, server_output) =
+            TCB::from_syn_cookie(listener.tuple, sock(), &ack, sizes).unwrap();
+        assert_eq!(server_tcb.state, TCBState::Estab);
+        assert_eq!(Some(server_tcb.advertised_window(false)), synack.window());
+        assert_eq!(server_tcb.seq_base, synack.seq_num().wrapping_add(1));
+        assert!(!server_tcb.negotiated_options().unwrap().sack_permitted);
+        server_tcb.set_ack_delay(Duration::from_secs(0));
+
+        // Data flows both ways as if the handshake had been done normally
+        server_input.send(TCBInput::Receive(ack)).unwrap();
+        server_tcb.handle_input_recv();
+        client_input.send(TCBInput::Send(vec![8; 100])).unwrap();
+        client_tcb.handle_input_recv();
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(TCB::recv(&server_output, 100).unwrap(), vec![8; 100]);
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(client_tcb.send_buffer.in_flight(), 0);
+    }
+
+    #[test]
+    fn handshake_legacy_peer() {
+        let (server_tuple, client_tuple, _, client_sock) = tcb_pair();
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (client_tcb, _, _) = client_tuple;
+
+        // The C tpp-client sends a bare 20 byte SYN
+        let mut syn = client_tcb.make_seg();
+        syn.set_flag(Flag::SYN);
+        syn.set_seq(client_tcb.seq_base);
+        server_input.send(TCBInput::Receive(syn)).unwrap();
+        server_tcb.handle_input_recv();
+
+        let synack = sock_recv(&client_sock);
+        assert!(synack.get_flag(Flag::SYN) && synack.get_flag(Flag::ACK));
+        assert!(synack.options().is_empty());
+        assert_eq!(synack.to_byte_vec().len(), HEADER_SIZE);
+        assert_eq!(server_tcb.negotiated_options(), None);
+        assert_eq!(server_tcb.send_mss(), MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn handshake_samples_rtt() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        assert!(server_tuple.0.rtt.srtt().is_some());
+        assert!(client_tuple.0.rtt.srtt().is_some());
+        assert!(server_tuple.0.rtt.rto() < Duration::from_secs(1));
+        assert_eq!(server_tuple.0.rto_deadline(), None);
+    }
+
+    #[test]
+    fn karn_skips_retransmitted_samples() {
+        let (server_tuple, client_tuple, server_sock, client_sock) = tcb_pair();
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _) = client_tuple;
+
+        client_input.send(TCBInput::SendSyn).unwrap();
+        client_tcb.handle_input_recv();
+        let deadline = client_tcb.rto_deadline().unwrap();
+        fire_timers(&mut client_tcb, deadline);
+        assert_eq!(client_tcb.rtt.rto(), Duration::from_secs(2));
+
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        let synacks = drain_sock(&client_sock);
+        assert_eq!(synacks.len(), 1);
+        for seg in synacks {
+            client_input.send(TCBInput::Receive(seg)).unwrap();
+            client_tcb.handle_input_recv();
+        }
+        assert_eq!(client_tcb.state, TCBState::Estab);
+        assert_eq!(client_tcb.rtt.srtt(), None);
+        assert_eq!(client_tcb.rtt.rto(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn per_segment_timers() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        net.tcb_mut(server).set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        net.tcb_mut(server).set_rto_bounds(Duration::from_millis(300), Duration::from_secs(5));
+        sim_connect(&mut net, client, server);
+        net.set_link(sim_addr(SERVER), sim_addr(CLIENT), dead_link());
+        net.capture();
+
+        // Two segments, then two more a little later, and all of them are lost
+        let start = net.now();
+        let rto = net.tcb(server).rtt.rto();
+        net.write(server, &vec![1; 2 * MAX_PAYLOAD_SIZE]);
+        net.run_for(Duration::from_millis(150));
+        net.write(server, &vec![2; 2 * MAX_PAYLOAD_SIZE]);
+        assert!(net.run_until(Duration::from_secs(1), |net| net.captured().len() == 8));
+        let sent = net.captured();
+        let seqs = |sent: &[(Instant, TCPTuple, Segment)]| {
+            sent.iter().map(|(_, _, seg)| seg.seq_num()).collect::<Vec<_>>()
+        };
+
+        // The first deadline resends both early segments together
+        assert_eq!(seqs(&sent[4..6]), seqs(&sent[..2]));
+        assert!(sent[4..6].iter().all(|&(at, _, _)| at == start + rto));
+        // The later segments keep their own deadline instead of waiting out another RTO
+        assert_eq!(seqs(&sent[6..]), seqs(&sent[2..4]));
+        let later = start + Duration::from_millis(150) + rto;
+        assert!(sent[6..].iter().all(|&(at, _, _)| at == later));
+    }
+
+    #[test]
+    fn resends_only_unacked_part() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (client_tcb, _, _client_output) = client_tuple;
+
+        let data: Vec<u8> = (0..2 * MAX_PAYLOAD_SIZE).map(|i| i as u8).collect();
+        server_input.send(TCBInput::Send(data.clone())).unwrap();
+        server_tcb.handle_input_recv();
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), 2);
+        let first = segments[0].seq_num();
+
+        // The peer trimmed the first segment to its window and acknowledged part of it
+        let mut ack = client_tcb.make_ack(None);
+        ack.set_ack_num(first.wrapping_add(100));
+        server_input.send(TCBInput::Receive(ack)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.send_buffer.in_flight(), data.len() - 100);
+
+        let deadline = server_tcb.rto_deadline().unwrap();
+        fire_timers(&mut server_tcb, deadline);
+        let resent = drain_sock(&client_sock);
+        assert_eq!(resent[0].seq_num(), first.wrapping_add(100));
+        assert_eq!(resent[0].payload(), &data[100..segments[0].payload().len()]);
+    }
+
+    #[test]
+    fn timestamps_sample_every_ack() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        // Both the sequence numbers and the timestamp clock wrap during the transfer
+        net.tcb_mut(server).set_initial_numbers(u32::MAX - 2, u32::MAX - 100);
+        net.tcb_mut(server).set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        net.tcb_mut(client).set_ack_delay(Duration::from_secs(0));
+        sim_connect(&mut net, client, server);
+        assert!(net.tcb(server).ts_enabled() && net.tcb(client).ts_enabled());
+        net.tcb_mut(server).rtt = RttEstimator::default();
+        net.capture();
+
+        // Everything is lost the first time round
+        net.set_link(sim_addr(SERVER), sim_addr(CLIENT), dead_link());
+        net.write(server, &vec![1; 3 * MAX_PAYLOAD_SIZE]);
+        net.set_link(sim_addr(SERVER), sim_addr(CLIENT), LinkConfig::default());
+        assert!(net.run_until(Duration::from_secs(10), |net| {
+            net.tcb(server).unacked_segs.is_empty()
+        }));
+        let from = |addr: u16| {
+            net.captured()
+                .iter()
+                .filter(|&&(_, tuple, _)| tuple.src == sim_addr(addr))
+                .map(|(_, _, seg)| seg.clone())
+                .collect::<Vec<_>>()
+        };
+        let (sent, mut acks) = (from(SERVER), from(CLIENT));
+        // Every read is followed by a window update, which repeats the ACK before it
+        acks.dedup_by_key(|ack| ack.ack_num());
+        assert_eq!((sent.len(), acks.len()), (6, 3));
+        let (old_val, _) = sent[0].timestamps().unwrap();
+        let (new_val, _) = sent[3].timestamps().unwrap();
+        assert!(new_val < old_val && seq_geq(new_val, old_val));
+
+        // Each ACK echoes the retransmission it answers, so unlike Karn's algorithm it's a sample
+        for (ack, seg) in acks.iter().zip(sent[3..].iter()) {
+            assert_eq!(ack.timestamps().unwrap().1, seg.timestamps().unwrap().0);
+        }
+        assert!(net.tcb(server).rtt.srtt().is_some());
+    }
+
+    #[test]
+    fn paws_rejects_old_duplicates() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.seq_base = u32::MAX - 2;
+        client_tuple.0.ts_offset = u32::MAX - 1000;
+        server_tuple.0.set_ack_delay(Duration::from_secs(0));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, server_output) = server_tuple;
+        let (client_tcb, _client_input, _) = client_tuple;
+        let data_seg = |seq: u32, byte: u8, tsval: u32| {
+            let mut seg = client_tcb.make_seg();
+            seg.set_flag(Flag::ACK);
+            seg.set_seq(seq);
+            seg.set_ack_num(client_tcb.ack_base);
+            seg.set_data(vec![byte; 10]);
+            seg.set_timestamps(tsval, 0);
+            seg
+        };
+
+        // The client's clock has just wrapped around
+        let seq = server_tcb.ack_base;
+        server_input.send(TCBInput::Receive(data_seg(seq, 1, 5))).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.ack_base, seq.wrapping_add(10));
+        sock_recv(&client_sock);
+
+        // A duplicate from before the wrap whose sequence number lands in the window again
+        let seq = server_tcb.ack_base;
+        server_input
+            .send(TCBInput::Receive(data_seg(seq, 2, u32::MAX - 5)))
+            .unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.ack_base, seq);
+        assert_eq!(sock_recv(&client_sock).ack_num(), seq);
+
+        server_input.send(TCBInput::Receive(data_seg(seq, 3, 6))).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.ack_base, seq.wrapping_add(10));
+        let mut expected = vec![1; 10];
+        expected.extend(vec![3; 10]);
+        assert_eq!(TCB::recv(&server_output, 20).unwrap(), expected);
+    }
+
+    #[test]
+    fn syn_retry_limit() {
+        let mut net = Network::new(0);
+        let (client, _) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        net.tcb_mut(client).set_retry_limits(3, 10);
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), dead_link());
+        net.capture();
+
+        // Nobody ever answers the SYN, which is resent with the RTO doubling each time
+        let start = net.now();
+        net.connect(client);
+        assert!(net.run_until(Duration::from_secs(60), |net| net.end(client).is_some()));
+        assert_eq!(net.end(client), Some(ConnectionError::TimedOut));
+        assert_eq!(net.tcb(client).state(), TCBState::Closed);
+        let sent: Vec<u64> = net.captured()
+            .iter()
+            .map(|&(at, _, _)| (at - start).as_secs())
+            .collect();
+        assert_eq!(sent, vec![0, 1, 3, 7]);
+        assert_eq!(net.now() - start, Duration::from_secs(15));
+    }
+
+    #[test]
+    fn data_retry_limit() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        net.tcb_mut(server).set_retry_limits(0, 2);
+        sim_connect(&mut net, client, server);
+
+        // The client has vanished, so the data is never acknowledged
+        net.set_link(sim_addr(SERVER), sim_addr(CLIENT), dead_link());
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), dead_link());
+        net.capture();
+        net.write(server, &[1, 2, 3]);
+        assert!(net.run_until(Duration::from_secs(60), |net| net.end(server).is_some()));
+        assert_eq!(net.end(server), Some(ConnectionError::TimedOut));
+        assert_eq!(net.captured().len(), 3);
+    }
+
+    #[test]
+    fn handshake_retransmit() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), dead_link());
+        let start = net.now();
+        net.connect(client);
+        // Lose the first SYN, then let the resend through
+        assert!(net.run_until(Duration::from_secs(10), |net| net.stats().lost == 1));
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), LinkConfig::default());
+        assert!(net.run_until(Duration::from_secs(10), |net| {
+            net.tcb(client).state() == TCBState::Estab && net.tcb(server).state() == TCBState::Estab
+        }));
+        assert!(net.now() - start >= Duration::from_secs(1));
+        assert_eq!(net.stats().sent, 4); // SYN twice, SYN-ACK, ACK
+    }
+
+    const CLIENT: u16 = 1000;
+    const SERVER: u16 = 2000;
+
+    fn sim_addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn dead_link() -> LinkConfig {
+        LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        }
+    }
+
+    /// Runs the handshake between two TCBs on a simulated network
+    fn sim_connect(net: &mut Network, client: usize, server: usize) {
+        net.connect(client);
+        assert!(net.run_until(Duration::from_secs(10), |net| {
+            net.tcb(client).state() == TCBState::Estab && net.tcb(server).state() == TCBState::Estab
+        }));
+    }
+
+    /// Congestion control that never limits the sender, for tests about the rest of the TCB
+    #[derive(Debug)]
+    pub struct FixedWindow(pub usize);
+
+    impl CongestionControl for FixedWindow {
+        fn cwnd(&self) -> usize {
+            self.0
+        }
+        fn set_mss(&mut self, _mss: usize) {}
+        fn on_ack(&mut self, _ack: u32, _acked: usize, _flight: usize, _now: Instant) -> bool {
+            false
+        }
+        fn on_dup_ack(&mut self, count: u32, _nxt: u32, _flight: usize, _now: Instant) -> bool {
+            count == ::congestion::DUPE_ACK_THRESHOLD
+        }
+        fn on_timeout(&mut self, _flight: usize, _now: Instant) {}
+    }
+
+    #[test]
+    fn send_test() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        server_tuple.0.seq_base = u32::MAX - 2; // Test wrapping around u32 boundaries
+        server_tuple.0.set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        server_tuple.0.set_nodelay(true);
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+
+        let (client_tcb, _client_input, _) = client_tuple;
+        let (mut server_tcb, server_input, _) = server_tuple;
+
+        let data_len = WINDOW_SIZE;
+        let str = String::from("Ok");
+        assert!(str.len() <= MAX_PAYLOAD_SIZE);
+        let mut data = (0..data_len).map(|r| r as u8).collect::<Vec<u8>>();
+        data.extend(str.clone().into_bytes());
+        server_input.send(TCBInput::Send(data)).unwrap();
+        server_tcb.handle_input_recv();
+
+        let mut segments = vec![];
+        for _ in 0..((data_len as f64 / MAX_PAYLOAD_SIZE as f64).ceil() as u32) {
+            segments.push(sock_recv(&client_sock));
+        }
+
+        let mut ack = client_tcb.make_seg();
+        ack.set_flag(Flag::ACK);
+        ack.set_ack_num(segments[1].seq_num());
+        server_input.send(TCBInput::Receive(ack)).unwrap();
+        server_tcb.handle_input_recv();
+        let next_seg = sock_recv(&client_sock);
+        assert_eq!(next_seg.payload(), str.into_bytes());
+
+        // Check for Duplicate ACK resends
+        for _ in 0..5 {
+            let mut ack = client_tcb.make_seg();
+            ack.set_flag(Flag::ACK);
+            ack.set_ack_num(segments[1].seq_num());
+            server_input.send(TCBInput::Receive(ack)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+
+        // Only the third duplicate triggers a fast retransmit
+        let resent = drain_sock(&client_sock);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq_num(), segments[1].seq_num());
+    }
+
+    #[test]
+    fn sack_retransmits_holes() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        server_tuple.0.set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+        assert!(server_tcb.sack_enabled() && client_tcb.sack_enabled());
+
+        server_input
+            .send(TCBInput::Send(vec![1; 6 * MAX_PAYLOAD_SIZE]))
+            .unwrap();
+        server_tcb.handle_input_recv();
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), 6);
+        let edge = |i: usize| segments[i].seq_num();
+        let end = edge(5).wrapping_add(MAX_PAYLOAD_SIZE as u32);
+
+        // Segments 1 and 3 go missing
+        for &i in &[0, 2, 4, 5] {
+            client_input
+                .send(TCBInput::Receive(segments[i].clone()))
+                .unwrap();
+            client_tcb.handle_input_recv();
+        }
+        let acks = drain_sock(&server_sock);
+        assert_eq!(acks.len(), 4);
+        assert!(acks.iter().all(|ack| ack.ack_num() == edge(1)));
+        assert!(acks[0].sack_blocks().is_empty());
+        assert_eq!(acks[1].sack_blocks(), &[(edge(2), edge(3))]);
+        assert_eq!(acks[2].sack_blocks(), &[(edge(4), edge(5)), (edge(2), edge(3))]);
+        assert_eq!(acks[3].sack_blocks(), &[(edge(4), end), (edge(2), edge(3))]);
+
+        // Only the holes come back, not the SACKed segments between them
+        for ack in acks {
+            server_input.send(TCBInput::Receive(ack)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        let resent = drain_sock(&client_sock);
+        let resent_seqs: Vec<u32> = resent.iter().map(|seg| seg.seq_num()).collect();
+        assert_eq!(resent_seqs, vec![edge(1), edge(3)]);
+
+        // Filling the first hole acknowledges up to the second
+        client_input
+            .send(TCBInput::Receive(resent[0].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        let ack = sock_recv(&server_sock);
+        assert_eq!(ack.ack_num(), edge(3));
+        assert_eq!(ack.sack_blocks(), &[(edge(4), end)]);
+
+        // A partial ACK in recovery doesn't resend the hole that's already been retransmitted
+        server_input.send(TCBInput::Receive(ack)).unwrap();
+        server_tcb.handle_input_recv();
+        server_tcb.retransmit_lost();
+        flush(&mut server_tcb);
+        assert!(drain_sock(&client_sock).is_empty());
+
+        client_input
+            .send(TCBInput::Receive(resent[1].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        let ack = sock_recv(&server_sock);
+        assert_eq!(ack.ack_num(), end);
+        assert!(ack.sack_blocks().is_empty());
+    }
+
+    /// A connection pair without SACK, with `count` full segments from the server in flight
+    fn dupe_ack_setup(count: usize) -> (TcbTup, TcbTup, UdpSocket, UdpSocket, Vec<Segment>) {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        server_tuple.0.set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        // Only duplicate ACKs may cause a retransmission
+        server_tuple.0.set_rto_bounds(Duration::from_secs(10), Duration::from_secs(60));
+        server_tuple.0.set_syn_options(SynOptions {
+            sack_permitted: false,
+            ..SynOptions::default()
+        });
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        server_tuple
+            .1
+            .send(TCBInput::Send(vec![1; count * MAX_PAYLOAD_SIZE]))
+            .unwrap();
+        server_tuple.0.handle_input_recv();
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), count);
+        (server_tuple, client_tuple, server_sock, client_sock, segments)
+    }
+
+    #[test]
+    fn fast_retransmit_resends_missing_segment() {
+        let (server_tuple, client_tuple, server_sock, client_sock, segments) = dupe_ack_setup(5);
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+
+        // Segment 1 goes missing
+        for &i in &[0, 2, 3, 4] {
+            client_input
+                .send(TCBInput::Receive(segments[i].clone()))
+                .unwrap();
+            client_tcb.handle_input_recv();
+        }
+        let acks = drain_sock(&server_sock);
+        assert_eq!(acks.len(), 4);
+        for ack in acks {
+            server_input.send(TCBInput::Receive(ack)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert_eq!(server_tcb.dupe_acks, 3);
+        let resent = drain_sock(&client_sock);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq_num(), segments[1].seq_num());
+        assert_eq!(resent[0].payload(), segments[1].payload());
+
+        client_input
+            .send(TCBInput::Receive(resent[0].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        let ack = sock_recv(&server_sock);
+        server_input.send(TCBInput::Receive(ack)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.dupe_acks, 0);
+        assert!(server_tcb.unacked_segs.is_empty());
+    }
+
+    #[test]
+    fn fast_retransmit_with_scaled_window() {
+        let start = Instant::now();
+        let big = 4 << 20;
+        let tuple = TCPTuple {
+            src: "127.0.0.1:1000".parse().unwrap(),
+            dst: "127.0.0.1:2000".parse().unwrap(),
+        };
+        let mut client = TCBCore::new(tuple, start);
+        let mut server = TCBCore::new(TCPTuple { src: tuple.dst, dst: tuple.src }, start);
+        client.set_window_sizes(big, big);
+        client.set_ack_delay(Duration::from_secs(0));
+        server.set_window_sizes(big, big);
+        server.set_congestion_control(Box::new(FixedWindow(big)));
+        server.set_rto_bounds(Duration::from_secs(10), Duration::from_secs(60));
+        server.set_syn_options(SynOptions {
+            sack_permitted: false,
+            ..SynOptions::default()
+        });
+        let syn = client.on_app_connect(start);
+        exchange(&mut client, &mut server, syn.transmit, start);
+        assert_eq!(server.send_shift(), 7);
+
+        let segments = server.on_app_write(&vec![1; 5 * MAX_PAYLOAD_SIZE], start).transmit;
+        assert_eq!(segments.len(), 5);
+        // Segment 1 goes missing, and the window in each duplicate ACK has to be scaled to match
+        let mut acks = vec![];
+        for &i in &[0, 2, 3, 4] {
+            let seg = Segment::parse(&segments[i]).unwrap();
+            acks.extend(client.on_segment(seg, start).transmit);
+        }
+        assert_eq!(acks.len(), 4);
+        let mut resent = vec![];
+        for ack in acks {
+            resent.extend(server.on_segment(Segment::parse(&ack).unwrap(), start).transmit);
+        }
+        assert_eq!(server.dupe_acks, 3);
+        assert_eq!(resent, vec![segments[1].clone()]);
+    }
+
+    #[test]
+    fn reordering_is_not_loss() {
+        let (server_tuple, client_tuple, server_sock, client_sock, segments) = dupe_ack_setup(5);
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+
+        // Segment 1 arrives late, which only costs one duplicate ACK
+        for &i in &[0, 2, 1, 3, 4] {
+            client_input
+                .send(TCBInput::Receive(segments[i].clone()))
+                .unwrap();
+            client_tcb.handle_input_recv();
+        }
+        for ack in drain_sock(&server_sock) {
+            server_input.send(TCBInput::Receive(ack)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert!(drain_sock(&client_sock).is_empty());
+        assert_eq!(server_tcb.dupe_acks, 0);
+        assert!(server_tcb.unacked_segs.is_empty());
+    }
+
+    #[test]
+    fn piggybacked_acks_are_not_dupes() {
+        let (server_tuple, client_tuple, server_sock, client_sock, segments) = dupe_ack_setup(4);
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+        client_tcb.set_nodelay(true);
+
+        // The client hasn't seen any of the server's data, so its own data repeats the same ACK
+        client_input
+            .send(TCBInput::Send(vec![2; 3 * MAX_PAYLOAD_SIZE]))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        let data = drain_sock(&server_sock);
+        assert_eq!(data.len(), 3);
+        for seg in data {
+            assert!(seg.get_flag(Flag::ACK));
+            assert_eq!(seg.ack_num(), segments[0].seq_num());
+            server_input.send(TCBInput::Receive(seg)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert_eq!(server_tcb.dupe_acks, 0);
+
+        // Nor are window updates, however many of them there are
+        for window in 1..5 {
+            let mut update = client_tcb.make_ack(None);
+            update.set_window((window * 1000) as u16);
+            server_input.send(TCBInput::Receive(update)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert_eq!(server_tcb.dupe_acks, 0);
+        assert!(drain_sock(&client_sock).iter().all(|seg| seg.payload().is_empty()));
+
+        // Pure ACKs that change nothing are duplicates
+        for _ in 0..3 {
+            let mut dupe = client_tcb.make_ack(None);
+            dupe.set_window(4000);
+            server_input.send(TCBInput::Receive(dupe)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert_eq!(server_tcb.dupe_acks, 3);
+        let resent = drain_sock(&client_sock);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq_num(), segments[0].seq_num());
+    }
+
+    #[test]
+    fn delayed_ack() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        server_tuple.0.set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        client_tuple.0.set_ack_delay(Duration::from_millis(50));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+
+        server_input
+            .send(TCBInput::Send(vec![2; 3 * MAX_PAYLOAD_SIZE]))
+            .unwrap();
+        server_tcb.handle_input_recv();
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), 3);
+        let end = segments[2].seq_num().wrapping_add(MAX_PAYLOAD_SIZE as u32);
+
+        // One ACK covers the first two segments
+        client_input
+            .send(TCBInput::Receive(segments[0].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        assert!(drain_sock(&server_sock).is_empty());
+        client_input
+            .send(TCBInput::Receive(segments[1].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        let acks = drain_sock(&server_sock);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].ack_num(), segments[2].seq_num());
+
+        // The third waits out the delay on its own
+        client_input
+            .send(TCBInput::Receive(segments[2].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        assert!(client_tcb.ack_deadline.is_some());
+        client_tcb.handle_input_recv();
+        let ack = sock_recv(&server_sock);
+        assert_eq!(ack.ack_num(), end);
+        assert!(client_tcb.ack_deadline.is_none());
+
+        // Out of order data is acknowledged at once, as is the segment filling the hole
+        server_input
+            .send(TCBInput::Send(vec![2; 2 * MAX_PAYLOAD_SIZE]))
+            .unwrap();
+        server_tcb.handle_input_recv();
+        let segments = drain_sock(&client_sock);
+        client_input
+            .send(TCBInput::Receive(segments[1].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        assert_eq!(sock_recv(&server_sock).ack_num(), end);
+        client_input
+            .send(TCBInput::Receive(segments[0].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        let ack = sock_recv(&server_sock);
+        assert_eq!(ack.ack_num(), end.wrapping_add(2 * MAX_PAYLOAD_SIZE as u32));
+        assert!(client_tcb.ack_deadline.is_none());
+    }
+
+    #[test]
+    fn nagle_coalesces_small_writes() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+
+        for _ in 0..5 {
+            server_input.send(TCBInput::Send(vec![4; 10])).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].payload().len(), 10);
+
+        // The rest goes out together once the first write is acknowledged
+        client_input
+            .send(TCBInput::Receive(segments[0].clone()))
+            .unwrap();
+        client_tcb.handle_input_recv();
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].payload().len(), 40);
+
+        server_tcb.set_nodelay(true);
+        for _ in 0..3 {
+            server_input.send(TCBInput::Send(vec![4; 10])).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert_eq!(drain_sock(&client_sock).len(), 3);
     }
 
     #[test]
-    fn full_handshake() {
+    fn slow_start_limits_initial_burst() {
         let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        server_tuple.0.set_nodelay(true);
         perform_handshake(
             &mut server_tuple,
             &mut client_tuple,
             &server_sock,
             &client_sock,
         );
-    }
-
-    #[test]
-    fn handshake_retransmit() {
-        let (server_tuple, client_tuple, server_sock, client_sock) = tcb_pair();
-        let (mut client_tcb, client_input, _) = client_tuple;
         let (mut server_tcb, server_input, _) = server_tuple;
-        let client_thread = thread::spawn(move || {
-            client_tcb.send_syn();
-            while client_tcb.state != TCBState::Estab {
-                client_tcb.handle_input_recv();
-            }
-        });
-        sock_recv(&server_sock);
-        let client_syn: Segment = sock_recv(&server_sock); // Wait for second send
-        assert!(client_syn.get_flag(Flag::SYN));
-        server_input.send(TCBInput::Receive(client_syn)).unwrap();
+        let (mut client_tcb, client_input, _) = client_tuple;
+
+        server_input.send(TCBInput::Send(vec![5; 30_000])).unwrap();
         server_tcb.handle_input_recv();
-        let server_synack: Segment = sock_recv(&client_sock);
-        client_input.send(TCBInput::Receive(server_synack)).unwrap();
+        let first_flight = drain_sock(&client_sock);
+        assert_eq!(first_flight.len(), 3);
 
-        client_thread.join().unwrap();
+        // Each ACK grows the window by a segment, so the next flight is twice as large
+        for seg in first_flight {
+            client_input.send(TCBInput::Receive(seg)).unwrap();
+            client_tcb.handle_input_recv();
+        }
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(drain_sock(&client_sock).len(), 6);
+    }
+
+    /// Collects segments until the socket goes quiet
+    fn drain_sock(sock: &UdpSocket) -> Vec<Segment> {
+        sock.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut segs = vec![];
+        let mut buf = vec![0; (1 << 16) - 1];
+        while let Ok((amt, _)) = sock.recv_from(&mut buf) {
+            segs.push(Segment::parse(&buf[..amt]).unwrap());
+        }
+        sock.set_read_timeout(None).unwrap();
+        segs
     }
 
     #[test]
-    fn send_test() {
+    fn send_respects_peer_window() {
         let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
-        server_tuple.0.seq_base = u32::max_value() - 2; // Test wrapping around u32 boundaries
         perform_handshake(
             &mut server_tuple,
             &mut client_tuple,
             &server_sock,
             &client_sock,
         );
-
-        let (client_tcb, _client_input, _) = client_tuple;
         let (mut server_tcb, server_input, _) = server_tuple;
+        let (client_tcb, _, _) = client_tuple;
 
-        let data_len = WINDOW_SIZE;
-        let str = String::from("Ok");
-        assert!(str.len() <= MAX_PAYLOAD_SIZE);
-        let mut data = (0..data_len).map(|r| r as u8).collect::<Vec<u8>>();
-        data.extend(str.clone().into_bytes());
-        server_input.send(TCBInput::Send(data)).unwrap();
+        let mut ack = client_tcb.make_seg();
+        ack.set_flag(Flag::ACK);
+        ack.set_ack_num(server_tcb.seq_base);
+        ack.set_window(2000);
+        server_input.send(TCBInput::Receive(ack)).unwrap();
         server_tcb.handle_input_recv();
 
-        let mut segments = vec![];
-        for _ in 0..((data_len as f64 / MAX_PAYLOAD_SIZE as f64).ceil() as u32) {
-            segments.push(sock_recv(&client_sock));
-        }
+        server_input.send(TCBInput::Send(vec![7; 5000])).unwrap();
+        server_tcb.handle_input_recv();
+        let sent: usize = drain_sock(&client_sock)
+            .iter()
+            .map(|seg| seg.payload().len())
+            .sum();
+        assert_eq!(sent, 2000);
 
         let mut ack = client_tcb.make_seg();
         ack.set_flag(Flag::ACK);
-        ack.set_ack_num(segments[1].seq_num());
+        ack.set_ack_num(server_tcb.seq_base.wrapping_add(2000));
+        ack.set_window(WINDOW_SIZE as u16);
         server_input.send(TCBInput::Receive(ack)).unwrap();
         server_tcb.handle_input_recv();
-        let next_seg = sock_recv(&client_sock);
-        assert_eq!(next_seg.payload(), str.into_bytes());
+        let sent: usize = drain_sock(&client_sock)
+            .iter()
+            .map(|seg| seg.payload().len())
+            .sum();
+        assert_eq!(sent, 3000);
+    }
 
-        // Check for Duplicate ACK resends
-        for _ in 0..5 {
-            let mut ack = client_tcb.make_seg();
-            ack.set_flag(Flag::ACK);
-            ack.set_ack_num(segments[1].seq_num());
+    #[test]
+    fn window_shift_fits_buffer() {
+        assert_eq!(window_shift(WINDOW_SIZE), 0);
+        assert_eq!(window_shift(u16::MAX as usize), 0);
+        assert_eq!(window_shift(u16::MAX as usize + 1), 1);
+        assert_eq!(window_shift(4 << 20), 7);
+        assert_eq!(window_shift(MAX_WINDOW), MAX_WINDOW_SHIFT);
+        assert_eq!(window_shift(usize::MAX), MAX_WINDOW_SHIFT);
+    }
+
+    #[test]
+    fn window_scaling() {
+        let big = 4 << 20;
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        server_tuple.0.set_window_sizes(big, big);
+        server_tuple.0.set_congestion_control(Box::new(FixedWindow(big)));
+        client_tuple.0.set_window_sizes(big, big);
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _client_output) = client_tuple;
+        assert_eq!(server_tcb.negotiated_options().unwrap().window_scale, Some(7));
+        assert_eq!(client_tcb.negotiated_options().unwrap().window_scale, Some(7));
+        assert_eq!(server_tcb.peer_window, big);
+        // The SYN-ACK's window is never scaled, the rest only shows up in the server's next segment
+        assert_eq!(client_tcb.peer_window, u16::MAX as usize);
+
+        // Well over 64 KB goes out without waiting for a single ACK
+        let data_len = 100_000;
+        server_input.send(TCBInput::Send(vec![4; data_len])).unwrap();
+        server_tcb.handle_input_recv();
+        let segments = drain_sock(&client_sock);
+        assert_eq!(segments.len(), data_len.div_ceil(MAX_PAYLOAD_SIZE));
+
+        for seg in segments {
+            client_input.send(TCBInput::Receive(seg)).unwrap();
+            client_tcb.handle_input_recv();
+        }
+        let acks = drain_sock(&server_sock);
+        let last = acks.last().unwrap();
+        assert_eq!(last.window(), Some(((big - data_len) >> 7) as u16));
+        for ack in acks {
             server_input.send(TCBInput::Receive(ack)).unwrap();
             server_tcb.handle_input_recv();
         }
+        assert!(server_tcb.unacked_segs.is_empty());
+        assert_eq!(server_tcb.peer_window, (big - data_len) >> 7 << 7);
+    }
 
-        let next_seg = sock_recv(&client_sock);
-        assert_eq!(next_seg.seq_num(), segments[1].seq_num());
+    #[test]
+    fn unscaled_peer_sees_64k() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        server_tuple.0.set_window_sizes(1 << 20, 1 << 20);
+        client_tuple.0.set_syn_options(SynOptions {
+            window_scale: None,
+            ..SynOptions::default()
+        });
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (server_tcb, _, _) = server_tuple;
+        let (client_tcb, _, _) = client_tuple;
+        assert_eq!(server_tcb.negotiated_options().unwrap().window_scale, None);
+        assert_eq!(client_tcb.peer_window, u16::MAX as usize);
+        assert_eq!(server_tcb.peer_window, WINDOW_SIZE);
+        assert_eq!(server_tcb.make_seg().window(), Some(u16::MAX));
+    }
 
-        let next_seg = sock_recv(&client_sock);
-        assert_eq!(next_seg.seq_num(), segments[1].seq_num());
+    #[test]
+    fn reordered_ack_keeps_newer_window() {
+        let start = Instant::now();
+        let tuple = TCPTuple {
+            src: "127.0.0.1:1000".parse().unwrap(),
+            dst: "127.0.0.1:2000".parse().unwrap(),
+        };
+        let mut client = TCBCore::new(tuple, start);
+        let mut server = TCBCore::new(TCPTuple { src: tuple.dst, dst: tuple.src }, start);
+        let syn = client.on_app_connect(start);
+        exchange(&mut client, &mut server, syn.transmit, start);
+        assert_eq!(server.peer_window, WINDOW_SIZE);
+
+        // The client sends some data, then a later ACK shutting its window overtakes it
+        let write = client.on_app_write(&[5; 100], start);
+        let data = Segment::parse(&write.transmit[0]).unwrap();
+        let mut shut = client.make_ack(None);
+        shut.set_window(0);
+        server.on_segment(shut, start);
+        assert_eq!(server.peer_window, 0);
+
+        // The data is still taken, but its stale window is not
+        let arrived = server.on_segment(data, start);
+        assert_eq!(arrived.deliver, vec![5; 100]);
+        assert_eq!(server.peer_window, 0);
+    }
+
+    #[test]
+    fn advertised_window_follows_reads() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, client_output) = client_tuple;
+
+        server_input.send(TCBInput::Send(vec![7; 3000])).unwrap();
+        server_tcb.handle_input_recv();
+        for seg in drain_sock(&client_sock) {
+            client_input.send(TCBInput::Receive(seg)).unwrap();
+            client_tcb.handle_input_recv();
+        }
+        let windows = drain_sock(&server_sock)
+            .iter()
+            .map(|ack| ack.window().unwrap() as usize)
+            .collect::<Vec<usize>>();
+        assert_eq!(windows, vec![WINDOW_SIZE - 1500, WINDOW_SIZE - 3000]);
+
+        // Reading the data back out should prompt an update announcing the space again
+        assert_eq!(TCB::recv(&client_output, 3000).unwrap(), vec![7; 3000]);
+        client_tcb.handle_input_recv();
+        let update = sock_recv(&server_sock);
+        assert!(update.get_flag(Flag::ACK));
+        assert_eq!(update.window(), Some(WINDOW_SIZE as u16));
     }
 
+    pub type E2eHandle = (Sender<TCBInput>, TCBOutput, UdpSocket);
     pub fn run_e2e_pair<F1, F2>(
         server_fn: F1,
         client_fn: F2,
-    ) -> ((Sender<TCBInput>, Receiver<u8>, UdpSocket), (Sender<TCBInput>, Receiver<u8>, UdpSocket))
+    ) -> (E2eHandle, E2eHandle)
     where
-        F1: FnOnce(TCB) -> () + Send + 'static,
-        F2: FnOnce(TCB) -> () + Send + 'static,
+        F1: FnOnce(TCB) + Send + 'static,
+        F2: FnOnce(TCB) + Send + 'static,
     {
         let (server_tuple, client_tuple, server_sock, client_sock) = tcb_pair();
         let (server_tcb, server_input, server_output) = server_tuple;
@@ -537,7 +2834,133 @@ pub mod tests {
     }
 
     #[test]
-    fn close_test() {
+    fn keepalive() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            probes: 2,
+        };
+        net.tcb_mut(server).set_keepalive(Some(keepalive));
+        sim_connect(&mut net, client, server);
+        net.capture();
+
+        // A live peer answers the probe, which restarts the idle timer
+        net.run_for(Duration::from_secs(90));
+        let seq_base = net.tcb(server).seq_base;
+        match net.captured() {
+            &[(_, _, ref probe), (_, _, ref ack)] => {
+                assert_eq!(probe.seq_num(), seq_base.wrapping_sub(1));
+                assert_eq!(probe.payload().len(), 1);
+                assert_eq!(ack.ack_num(), seq_base);
+            }
+            captured => panic!("Expected a probe and its ACK, got {:?}", captured),
+        }
+        assert_eq!(net.tcb(server).keepalive_probes, 0);
+
+        // A vanished one is given up on after the last probe goes unanswered
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), dead_link());
+        let heard = net.captured()[1].0 + LinkConfig::default().delay;
+        assert!(net.run_until(Duration::from_secs(600), |net| net.end(server).is_some()));
+        assert_eq!(net.end(server), Some(ConnectionError::Unreachable));
+        assert_eq!(net.tcb(server).state(), TCBState::Closed);
+        let probes: Vec<Duration> = net.captured()[2..]
+            .iter()
+            .filter(|&&(_, tuple, _)| tuple.src == sim_addr(SERVER))
+            .map(|&(at, _, _)| at - heard)
+            .collect();
+        assert_eq!(probes, vec![keepalive.idle, keepalive.idle + keepalive.interval]);
+        assert_eq!(net.now() - heard, keepalive.idle + 2 * keepalive.interval);
+    }
+
+    #[test]
+    fn zero_window_persist() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        net.tcb_mut(server).set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        net.tcb_mut(server).set_nodelay(true);
+        net.tcb_mut(client).set_ack_delay(Duration::from_secs(0));
+        sim_connect(&mut net, client, server);
+
+        // The client's application stalls, so its buffer fills and the window closes
+        net.set_reading(client, false);
+        net.write(server, &vec![6; WINDOW_SIZE + 3000]);
+        assert!(net.run_until(Duration::from_secs(1), |net| net.tcb(server).peer_window == 0));
+        assert_eq!(net.tcb(server).send_buffer.unsent(), 3000);
+        assert!(net.tcb(server).persist_deadline.is_some());
+
+        // Probes back off while the window stays shut, but the connection lives on
+        net.capture();
+        assert!(net.run_until(Duration::from_secs(600), |net| {
+            net.tcb(server).persist_backoff == 3
+        }));
+        net.run_for(Duration::from_secs(1));
+        assert_eq!(net.captured().len(), 6);
+        for pair in net.captured().chunks(2) {
+            assert_eq!(pair[0].2.payload().len(), 1);
+            assert_eq!(pair[1].2.window(), Some(0));
+        }
+        assert_eq!(net.tcb(server).persist_unanswered, 0);
+        assert_eq!(net.tcb(server).state(), TCBState::Estab);
+
+        // The application resumes, but the window update it prompts is lost
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), dead_link());
+        net.set_reading(client, true);
+        let update = &net.captured().last().unwrap().2;
+        assert_eq!(update.window(), Some(WINDOW_SIZE as u16));
+        assert_eq!(net.stats().lost, 1);
+        net.set_link(sim_addr(CLIENT), sim_addr(SERVER), LinkConfig::default());
+
+        // The next probe finds the open window and the rest of the data follows
+        assert!(net.run_until(Duration::from_secs(600), |net| {
+            net.received(client).len() == WINDOW_SIZE + 3000
+        }));
+        assert!(net.received(client).iter().all(|&byte| byte == 6));
+        net.run_for(Duration::from_secs(1));
+        assert_eq!(net.tcb(server).send_buffer.len(), 0);
+        assert!(net.tcb(server).persist_deadline.is_none());
+    }
+
+    #[test]
+    fn zero_window_takes_acks() {
+        let mut net = Network::new(0);
+        let (client, server) = net.add_pair(sim_addr(CLIENT), sim_addr(SERVER));
+        net.tcb_mut(server).set_congestion_control(Box::new(FixedWindow(WINDOW_SIZE)));
+        sim_connect(&mut net, client, server);
+
+        // Both ends send at once, but the client's application has stalled, so its window
+        // closes while its own data is still waiting to be acknowledged
+        net.set_reading(client, false);
+        net.capture();
+        net.write(server, &vec![6; WINDOW_SIZE + 3000]);
+        net.write(client, &vec![7; 20000]);
+        assert!(net.run_until(Duration::from_secs(1), |net| {
+            net.received(server).len() == 20000 && net.tcb(client).send_buffer.is_empty()
+        }));
+        assert_eq!(net.tcb(server).peer_window, 0);
+        let mut sent: Vec<u32> = net.captured()
+            .iter()
+            .filter(|&&(_, tuple, ref seg)| {
+                tuple.src == sim_addr(CLIENT) && !seg.payload().is_empty()
+            })
+            .map(|(_, _, seg)| seg.seq_num())
+            .collect();
+        let segs = sent.len();
+        sent.dedup();
+        assert_eq!(sent.len(), segs);
+
+        // Once the application catches up the rest of the server's data follows
+        net.set_reading(client, true);
+        assert!(net.run_until(Duration::from_secs(600), |net| {
+            net.received(client).len() == WINDOW_SIZE + 3000
+        }));
+        assert!(net.received(client).iter().all(|&byte| byte == 6));
+        assert!(net.received(server).iter().all(|&byte| byte == 7));
+    }
+
+    #[test]
+    fn abort_test() {
         let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
         perform_handshake(
             &mut server_tuple,
@@ -546,16 +2969,398 @@ pub mod tests {
             &client_sock,
         );
 
-        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut server_tcb, server_input, server_output) = server_tuple;
+        let (mut client_tcb, client_input, client_output) = client_tuple;
+
+        client_input.send(TCBInput::Abort).unwrap();
+        client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::Closed);
+        assert_eq!(client_output.recv(), Err(ConnectionError::Aborted));
+
+        let client_rst = sock_recv(&server_sock);
+        assert!(client_rst.get_flag(Flag::RST));
+        server_input.send(TCBInput::Receive(client_rst)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::Closed);
+        assert_eq!(server_output.recv(), Err(ConnectionError::Reset));
+
+        // Once the FIN is out the RST has to come after it, or the peer won't believe it
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, server_output) = server_tuple;
         let (mut client_tcb, client_input, _) = client_tuple;
+        client_input.send(TCBInput::Close).unwrap();
+        client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::FinWait1);
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(server_tcb.state, TCBState::CloseWait);
+
+        client_input.send(TCBInput::Abort).unwrap();
+        client_tcb.handle_input_recv();
+        let client_rst = sock_recv(&server_sock);
+        assert_eq!(client_rst.seq_num(), server_tcb.ack_base);
+        server_input.send(TCBInput::Receive(client_rst)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::Closed);
+        assert_eq!(server_output.recv(), Err(ConnectionError::Reset));
+    }
+
+    #[test]
+    fn reset_outside_window_ignored() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+
+        let mut rst = server_tcb.make_seg();
+        rst.set_flag(Flag::RST);
+        rst.set_seq(server_tcb.ack_base.wrapping_sub(10));
+        server_input.send(TCBInput::Receive(rst)).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::Estab);
+    }
+
+    #[test]
+    fn reset_reply_numbers() {
+        let tuple = TCPTuple {
+            src: "127.0.0.1:1000".parse().unwrap(),
+            dst: "127.0.0.1:2000".parse().unwrap(),
+        };
+        let mut data = Segment::new(2000, 1000);
+        data.set_seq(50);
+        data.set_data(vec![1, 2, 3]);
+        let rst = TCBCore::reset_reply(&tuple, &data).unwrap();
+        assert!(rst.get_flag(Flag::RST) && rst.get_flag(Flag::ACK));
+        assert_eq!(rst.ack_num(), 53);
+        assert_eq!(rst.dst_port(), 2000);
+
+        let mut ack = Segment::new(2000, 1000);
+        ack.set_flag(Flag::ACK);
+        ack.set_ack_num(77);
+        let rst = TCBCore::reset_reply(&tuple, &ack).unwrap();
+        assert!(!rst.get_flag(Flag::ACK));
+        assert_eq!(rst.seq_num(), 77);
+
+        assert!(TCBCore::reset_reply(&tuple, &rst).is_none());
+    }
+
+    /// Carries out whatever a test left the core to do by calling into it directly
+    fn flush(tcb: &mut TCB) {
+        let actions = tcb.core.take_actions();
+        tcb.apply(actions);
+    }
+
+    /// Fires the timers due by `now`, as the driver would once it got there
+    fn fire_timers(tcb: &mut TCB, now: Instant) {
+        let actions = tcb.on_timeout(now);
+        tcb.apply(actions);
+    }
+
+    /// Passes whatever arrived on `sock` to `tcb`
+    fn deliver(tcb: &mut TCB, input: &Sender<TCBInput>, sock: &UdpSocket) {
+        for seg in drain_sock(sock) {
+            input.send(TCBInput::Receive(seg)).unwrap();
+            tcb.handle_input_recv();
+        }
+    }
+
+    #[test]
+    fn close_test() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+
+        let (mut server_tcb, server_input, server_output) = server_tuple;
+        let (mut client_tcb, client_input, client_output) = client_tuple;
+        client_tcb.set_time_wait(Duration::from_millis(50));
 
         client_input.send(TCBInput::Close).unwrap();
         client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::FinWait1);
         let client_fin = sock_recv(&server_sock);
+        assert!(client_fin.get_flag(Flag::FIN));
         server_input.send(TCBInput::Receive(client_fin)).unwrap();
         server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::CloseWait);
+        assert_eq!(server_output.recv(), Err(ConnectionError::Closed));
 
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(client_tcb.state, TCBState::FinWait2);
+
+        server_input.send(TCBInput::Close).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::LastAck);
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(client_tcb.state, TCBState::TimeWait);
+        deliver(&mut server_tcb, &server_input, &server_sock);
         assert_eq!(server_tcb.state, TCBState::Closed);
+
+        // Straight to the core, so TIME-WAIT can't run out first
+        let mut rst = server_tcb.make_seg();
+        rst.set_flag(Flag::RST);
+        let now = client_tcb.now;
+        let actions = client_tcb.on_segment(rst, now);
+        assert_eq!(actions.end, None);
+        assert_eq!(client_tcb.state, TCBState::TimeWait);
+
+        client_tcb.run_tcp();
+        assert_eq!(client_tcb.state, TCBState::Closed);
+        assert_eq!(client_output.recv(), Err(ConnectionError::Closed));
+    }
+
+    #[test]
+    fn read_takes_what_has_arrived() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, client_output) = client_tuple;
+        client_tcb.set_ack_delay(Duration::from_secs(0));
+
+        let data: Vec<u8> = (0..100).collect();
+        server_input.send(TCBInput::Send(data.clone())).unwrap();
+        server_tcb.handle_input_recv();
+        deliver(&mut client_tcb, &client_input, &client_sock);
+
+        // Short reads don't wait for the rest of the buffer to fill
+        let mut buf = [0; 40];
+        assert_eq!(client_output.read(&mut buf), Ok(40));
+        assert_eq!(&buf[..], &data[..40]);
+        let mut buf = [0; 1000];
+        assert_eq!(client_output.read(&mut buf), Ok(60));
+        assert_eq!(&buf[..60], &data[40..]);
+        // The TCB hears about both reads at once
+        client_tcb.handle_input_recv();
+        assert_eq!(client_tcb.unread, 0);
+        deliver(&mut server_tcb, &server_input, &server_sock);
+
+        // With nothing left a read waits for the next segment
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 1000];
+            let amt = client_output.read(&mut buf).unwrap();
+            (buf[..amt].to_vec(), client_output)
+        });
+        thread::sleep(Duration::from_millis(50));
+        server_input.send(TCBInput::Send(vec![7; 50])).unwrap();
+        server_tcb.handle_input_recv();
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        let (read, client_output) = reader.join().unwrap();
+        assert_eq!(read, vec![7; 50]);
+        client_tcb.handle_input_recv();
+        deliver(&mut server_tcb, &server_input, &server_sock);
+
+        // Data already delivered is still read before the reset
+        server_input.send(TCBInput::Send(vec![8; 30])).unwrap();
+        server_tcb.handle_input_recv();
+        server_input.send(TCBInput::Abort).unwrap();
+        server_tcb.handle_input_recv();
+        deliver(&mut client_tcb, &client_input, &client_sock);
         assert_eq!(client_tcb.state, TCBState::Closed);
+        assert_eq!(TCB::recv(&client_output, 30), Ok(vec![8; 30]));
+        assert_eq!(client_output.read(&mut buf), Err(ConnectionError::Reset));
+    }
+
+    /// Hands `to_b` to `b`, and everything the two sides send in reply to each other, until both
+    /// go quiet.  Returns the data `a` and `b` delivered.
+    fn exchange(
+        a: &mut TCBCore,
+        b: &mut TCBCore,
+        mut to_b: Vec<Vec<u8>>,
+        now: Instant,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let (mut a_got, mut b_got) = (vec![], vec![]);
+        while !to_b.is_empty() {
+            let mut to_a = vec![];
+            for bytes in to_b {
+                let actions = b.on_segment(Segment::parse(&bytes).unwrap(), now);
+                to_a.extend(actions.transmit);
+                b_got.extend(actions.deliver);
+            }
+            to_b = vec![];
+            for bytes in to_a {
+                let actions = a.on_segment(Segment::parse(&bytes).unwrap(), now);
+                to_b.extend(actions.transmit);
+                a_got.extend(actions.deliver);
+            }
+        }
+        (a_got, b_got)
+    }
+
+    #[test]
+    fn core_runs_on_virtual_time() {
+        let start = Instant::now();
+        let tuple = TCPTuple {
+            src: "127.0.0.1:1000".parse().unwrap(),
+            dst: "127.0.0.1:2000".parse().unwrap(),
+        };
+        let mut client = TCBCore::new(tuple, start);
+        let mut server = TCBCore::new(TCPTuple { src: tuple.dst, dst: tuple.src }, start);
+
+        let syn = client.on_app_connect(start);
+        assert!(syn.deadline.is_some());
+        exchange(&mut client, &mut server, syn.transmit, start);
+        assert_eq!(client.state(), TCBState::Estab);
+        assert_eq!(server.state(), TCBState::Estab);
+
+        // A lone small segment is only acknowledged once the delayed ACK timer runs out
+        let now = start + Duration::from_millis(10);
+        let write = server.on_app_write(&[1; 100], now);
+        assert_eq!(write.transmit.len(), 1);
+        let arrived = client.on_segment(Segment::parse(&write.transmit[0]).unwrap(), now);
+        assert_eq!(arrived.deliver, vec![1; 100]);
+        assert!(arrived.transmit.is_empty());
+        assert_eq!(arrived.deadline, Some(now + Duration::from_millis(ACK_DELAY)));
+        assert!(client.on_timeout(now).transmit.is_empty());
+        let ack = client.on_timeout(arrived.deadline.unwrap());
+        assert_eq!(ack.transmit.len(), 1);
+        exchange(&mut client, &mut server, ack.transmit, arrived.deadline.unwrap());
+        assert!(server.unacked_segs.is_empty());
+
+        // A lost segment comes back once its RTO is up, however little real time has passed
+        let now = start + Duration::from_secs(1);
+        let lost = server.on_app_write(&[2; 100], now);
+        let rto = lost.deadline.unwrap();
+        assert!(rto > now);
+        let resent = server.on_timeout(rto);
+        assert_eq!(resent.transmit.len(), 1);
+        let (_, delivered) = exchange(&mut server, &mut client, resent.transmit, rto);
+        assert_eq!(delivered, vec![2; 100]);
+    }
+
+    #[test]
+    fn dropped_tcb_ends_stream() {
+        let (_, (client_tcb, _, client_output), _, _) = tcb_pair();
+        let reader = thread::spawn(move || client_output.recv());
+        thread::sleep(Duration::from_millis(50));
+        drop(client_tcb);
+        assert_eq!(reader.join().unwrap(), Err(ConnectionError::Closed));
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, _) = client_tuple;
+
+        client_input.send(TCBInput::Close).unwrap();
+        client_tcb.handle_input_recv();
+        server_input.send(TCBInput::Close).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(client_tcb.state, TCBState::FinWait1);
+        assert_eq!(server_tcb.state, TCBState::FinWait1);
+
+        // Each FIN crosses the other, so both ends see a FIN before the ACK of their own
+        let to_client = drain_sock(&client_sock);
+        let to_server = drain_sock(&server_sock);
+        for seg in to_client {
+            client_input.send(TCBInput::Receive(seg)).unwrap();
+            client_tcb.handle_input_recv();
+        }
+        for seg in to_server {
+            server_input.send(TCBInput::Receive(seg)).unwrap();
+            server_tcb.handle_input_recv();
+        }
+        assert_eq!(client_tcb.state, TCBState::Closing);
+        assert_eq!(server_tcb.state, TCBState::Closing);
+
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(client_tcb.state, TCBState::TimeWait);
+        assert_eq!(server_tcb.state, TCBState::TimeWait);
+    }
+
+    #[test]
+    fn fin_waits_for_data() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        client_tuple.0.set_ack_delay(Duration::from_secs(0));
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, client_output) = client_tuple;
+
+        server_input.send(TCBInput::Send(vec![3; 2000])).unwrap();
+        server_tcb.handle_input_recv();
+        server_input.send(TCBInput::Close).unwrap();
+        server_tcb.handle_input_recv();
+        assert_eq!(server_tcb.state, TCBState::Estab);
+
+        // Lose the data, the FIN can't go out until it has been resent and acknowledged
+        assert!(drain_sock(&client_sock).iter().all(|seg| !seg.get_flag(Flag::FIN)));
+        server_tcb.handle_resend();
+        flush(&mut server_tcb);
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(server_tcb.state, TCBState::Estab);
+        server_tcb.handle_resend();
+        flush(&mut server_tcb);
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(server_tcb.state, TCBState::FinWait1);
+
+        // A lost FIN is retransmitted like any other segment
+        let fin = sock_recv(&client_sock);
+        assert!(fin.get_flag(Flag::FIN));
+        server_tcb.handle_resend();
+        flush(&mut server_tcb);
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(client_tcb.state, TCBState::CloseWait);
+        assert_eq!(TCB::recv(&client_output, 2000).unwrap(), vec![3; 2000]);
+        assert_eq!(client_output.recv(), Err(ConnectionError::Closed));
+
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        assert_eq!(server_tcb.state, TCBState::FinWait2);
+    }
+
+    #[test]
+    fn half_close() {
+        let (mut server_tuple, mut client_tuple, server_sock, client_sock) = tcb_pair();
+        perform_handshake(
+            &mut server_tuple,
+            &mut client_tuple,
+            &server_sock,
+            &client_sock,
+        );
+        let (mut server_tcb, server_input, _) = server_tuple;
+        let (mut client_tcb, client_input, client_output) = client_tuple;
+
+        client_input.send(TCBInput::Close).unwrap();
+        client_tcb.handle_input_recv();
+        deliver(&mut server_tcb, &server_input, &server_sock);
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(client_tcb.state, TCBState::FinWait2);
+        assert_eq!(server_tcb.state, TCBState::CloseWait);
+
+        // The server can keep sending after the client has closed its half
+        server_input.send(TCBInput::Send(vec![1, 2, 3])).unwrap();
+        server_tcb.handle_input_recv();
+        deliver(&mut client_tcb, &client_input, &client_sock);
+        assert_eq!(TCB::recv(&client_output, 3).unwrap(), vec![1, 2, 3]);
     }
 }